@@ -0,0 +1,35 @@
+use std::path::Path;
+
+/// Expose the resolved ebur128 version as `EBUR128_VERSION`, for `--version`
+///
+/// ebur128 has no version API and no `links` key (so no `DEP_*` metadata), and Cargo only
+/// passes the version of the crate being built. The resolved version is read from Cargo.lock
+/// instead: it is not committed, but cargo writes it next to the workspace manifest before
+/// running build scripts. That is this directory or, when built inside a workspace, one of
+/// its ancestors. Without a lock file (e.g. a vendored build with the lock removed) the
+/// version is "unknown".
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let version = Path::new(&manifest_dir)
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lock| lock.is_file())
+        .and_then(|lock| {
+            println!("cargo:rerun-if-changed={}", lock.display());
+            std::fs::read_to_string(&lock).ok()
+        })
+        .and_then(|lock| ebur128_version(&lock))
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-env=EBUR128_VERSION={version}");
+}
+
+/// Version of the ebur128 package in Cargo.lock
+fn ebur128_version(lock: &str) -> Option<String> {
+    let mut lines = lock
+        .lines()
+        .skip_while(|line| *line != "name = \"ebur128\"");
+    lines.next()?;
+    let version = lines.next()?.strip_prefix("version = \"")?;
+    Some(version.trim_end_matches('"').to_owned())
+}
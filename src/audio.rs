@@ -1,15 +1,46 @@
+use std::io::Read;
+use std::ops::ControlFlow;
 use std::path::Path;
 
 use ffmpeg_next as ffmpeg;
 use log::warn;
 use symphonia::core::audio::{AudioBufferRef, SampleBuffer, SignalSpec};
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 use symphonia::core::units::Duration;
 
+#[derive(Debug, thiserror::Error)]
+pub enum AudioError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("FLAC error: {0}")]
+    Flac(#[from] claxon::Error),
+    #[error("Decoding error: {0}")]
+    Symphonia(#[from] SymphoniaError),
+    #[error("FFmpeg error: {0}")]
+    Ffmpeg(#[from] ffmpeg::Error),
+    #[error("Unsupported bit depth: {0}")]
+    BitDepth(u32),
+    #[error("No audio track found")]
+    NoTrack,
+    #[error("Missing stream info: {0}")]
+    MissingInfo(&'static str),
+    #[error("Channel count changed from {0} to {1}")]
+    ChannelMismatch(u32, u32),
+    #[error("Sample rate changed from {0} to {1} Hz")]
+    RateMismatch(u32, u32),
+    #[error("Invalid stream format: {0} channels at {1} Hz")]
+    InvalidFormat(u32, u32),
+    #[error("Window starts at or after the end of the audio ({0:.1} s)")]
+    WindowPastEnd(f64),
+    #[error("DSD (DSF/DFF) is not supported, convert it to PCM first (e.g. ffmpeg -i song.dsf song.flac)")]
+    Dsd,
+}
+
 pub enum Audio {
     S16(Vec<i16>),
     S32(Vec<i32>),
@@ -26,11 +57,22 @@ impl Audio {
             Audio::F64(x) => x.extend_from_slice(samples.get_f64().unwrap()),
         }
     }
+
+    /// Gets an immutable slice of all samples.
+    pub fn samples(&self) -> AudioRef<'_> {
+        match self {
+            Audio::S16(x) => AudioRef::from_i16(x),
+            Audio::S32(x) => AudioRef::from_i32(x),
+            Audio::F32(x) => AudioRef::from_f32(x),
+            Audio::F64(x) => AudioRef::from_f64(x),
+        }
+    }
 }
 
 #[derive(Clone)]
 /// Similar as [symphonia::core::audio::AudioBufferRef] with reduced type-set
 pub enum AudioRef<'a> {
+    // 8 bit audio comes as S16, see [AudioSampleBuffer::new]
     //S8(&'a [i8]),
     S16(&'a [i16]),
     S32(&'a [i32]),
@@ -55,6 +97,30 @@ impl<'a> AudioRef<'a> {
         Self::F64(data)
     }
 
+    /// Number of (interleaved) samples
+    pub fn len(&self) -> usize {
+        match self {
+            AudioRef::S16(x) => x.len(),
+            AudioRef::S32(x) => x.len(),
+            AudioRef::F32(x) => x.len(),
+            AudioRef::F64(x) => x.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sub-slice of (interleaved) samples
+    pub fn slice(&self, range: std::ops::Range<usize>) -> AudioRef<'a> {
+        match self {
+            AudioRef::S16(x) => AudioRef::S16(&x[range]),
+            AudioRef::S32(x) => AudioRef::S32(&x[range]),
+            AudioRef::F32(x) => AudioRef::F32(&x[range]),
+            AudioRef::F64(x) => AudioRef::F64(&x[range]),
+        }
+    }
+
     fn get_i16(&self) -> Option<&[i16]> {
         match self {
             AudioRef::S16(x) => Some(x),
@@ -85,6 +151,21 @@ impl<'a> AudioRef<'a> {
 }
 
 impl<'a> AudioRef<'a> {
+    /// Fold interleaved frames of `channels` channels to stereo (normalized f64)
+    ///
+    /// Uses ITU-R BS.775 coefficients, see [can_downmix] for supported layouts.
+    /// The result is not scaled down, so it can exceed full scale.
+    pub fn downmix_stereo(&self, channels: u32) -> Option<Vec<f64>> {
+        let coefficients = downmix_coefficients(channels)?;
+        Some(match *self {
+            AudioRef::S16(x) => downmix(x, coefficients, |s| s as f64 / 32768.0),
+            // FLAC and 24 bit samples are left-aligned
+            AudioRef::S32(x) => downmix(x, coefficients, |s| s as f64 / 2147483648.0),
+            AudioRef::F32(x) => downmix(x, coefficients, |s| s as f64),
+            AudioRef::F64(x) => downmix(x, coefficients, |s| s),
+        })
+    }
+
     pub fn to_owned(&'a self) -> Audio {
         match *self {
             AudioRef::S16(x) => Audio::S16(x.to_owned()),
@@ -95,6 +176,56 @@ impl<'a> AudioRef<'a> {
     }
 }
 
+/// Whether [AudioRef::downmix_stereo] supports `channels` channels
+///
+/// These are 3.0, quad, 5.0, 5.1, 6.1 and 7.1 in WAVE channel order (also used by FLAC
+/// and FFmpeg defaults).
+pub fn can_downmix(channels: u32) -> bool {
+    downmix_coefficients(channels).is_some()
+}
+
+/// (left, right) downmix coefficient of every channel; LFE is dropped
+fn downmix_coefficients(channels: u32) -> Option<&'static [(f64, f64)]> {
+    const C: f64 = std::f64::consts::FRAC_1_SQRT_2;
+    const L: (f64, f64) = (1.0, 0.0);
+    const R: (f64, f64) = (0.0, 1.0);
+    const CENTER: (f64, f64) = (C, C);
+    const LFE: (f64, f64) = (0.0, 0.0);
+    const LS: (f64, f64) = (C, 0.0);
+    const RS: (f64, f64) = (0.0, C);
+    Some(match channels {
+        3 => &[L, R, CENTER],
+        4 => &[L, R, LS, RS],
+        5 => &[L, R, CENTER, LS, RS],
+        6 => &[L, R, CENTER, LFE, LS, RS],
+        // back center goes to both sides
+        7 => &[L, R, CENTER, LFE, (0.5, 0.5), LS, RS],
+        // back and side surrounds
+        8 => &[L, R, CENTER, LFE, LS, RS, LS, RS],
+        _ => return None,
+    })
+}
+
+fn downmix<T: Copy>(
+    samples: &[T],
+    coefficients: &[(f64, f64)],
+    to_f64: impl Fn(T) -> f64,
+) -> Vec<f64> {
+    samples
+        .chunks_exact(coefficients.len())
+        .flat_map(|frame| {
+            let (left, right) = frame.iter().zip(coefficients).fold(
+                (0.0, 0.0),
+                |(left, right), (&sample, &(to_left, to_right))| {
+                    let sample = to_f64(sample);
+                    (left + to_left * sample, right + to_right * sample)
+                },
+            );
+            [left, right]
+        })
+        .collect()
+}
+
 /// AUDIO with some audioinfo
 pub struct Audi {
     /// raw audio data
@@ -107,71 +238,259 @@ pub struct Audi {
     pub bits: u8,
 }
 
+/// Info about decoded audio stream
+#[derive(Clone, Copy, Debug)]
+pub struct AudioInfo {
+    /// Number of channels
+    pub channels: u32,
+    /// sampling rate in hz
+    pub sample_rate: u32,
+    /// bit 16 or 24 bit
+    pub bits: u8,
+}
+
+/// Path that reads audio from standard input
+pub const STDIN: &str = "-";
+
+/// Number of frames passed to sink at once when decoder has no packets of its own (claxon)
+const CHUNK_FRAMES: usize = 4096;
+
+/// Opened symphonia stream with decoder for default track
+struct GenericStream {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+}
+
+/// Opened ffmpeg input with decoder for best audio stream, converting to interleaved f32
+struct FfmpegStream {
+    format: ffmpeg::format::context::Input,
+    decoder: ffmpeg::decoder::Audio,
+    resampler: ffmpeg::software::resampling::Context,
+    track_index: usize,
+    info: AudioInfo,
+}
+
 impl Audi {
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
-        if path.as_ref().extension().unwrap() == "flac" {
-            return Self::from_flac_file(path);
+    /// Decode whole file into memory
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, AudioError> {
+        let mut audio: Option<Audio> = None;
+        let info = Self::stream_path(path, |_, samples| {
+            if let Some(s) = &mut audio {
+                s.extend_from_slice(samples);
+            } else {
+                audio = Some(samples.to_owned())
+            }
+            Ok::<_, AudioError>(ControlFlow::Continue(()))
+        })?;
+
+        Ok(Audi {
+            audio: audio.ok_or(AudioError::MissingInfo("audio"))?,
+            channels: info.channels,
+            sample_rate: info.sample_rate,
+            bits: info.bits,
+        })
+    }
+
+    /// Decode file and pass interleaved samples to `sink` as they are decoded,
+    /// so only one packet is kept in memory. [STDIN] (`-`) reads standard input.
+    ///
+    /// Decoding stops early once `sink` returns [ControlFlow::Break].
+    pub fn stream_path<P, F, E>(path: P, mut sink: F) -> Result<AudioInfo, E>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&AudioInfo, AudioRef) -> Result<ControlFlow<()>, E>,
+        E: From<AudioError>,
+    {
+        if path.as_ref() == Path::new(STDIN) {
+            // formats may need to seek, so stdin is buffered completely
+            let mut data = Vec::new();
+            std::io::stdin()
+                .lock()
+                .read_to_end(&mut data)
+                .map_err(AudioError::from)?;
+            let stream = Self::open_generic(Box::new(std::io::Cursor::new(data)), &Hint::new())?;
+            return Self::stream_generic(stream, sink);
         }
-        warn!("Fallback to generic Audio reader");
-        match Self::from_generic_file(path.as_ref()) {
-            Ok(x) => x,
-            Err(_) => Self::from_ffmpeg(path),
+        // fail cleanly on missing/unreadable files
+        std::fs::File::open(path.as_ref()).map_err(AudioError::from)?;
+        if extension(path.as_ref()).as_deref() == Some("flac") {
+            // claxon is strict (e.g. about a leading ID3v2 tag), so symphonia gets a try
+            // as long as sink has not seen any samples
+            let mut fed = false;
+            let result = Self::stream_flac_file(path.as_ref(), |info, samples| {
+                fed = true;
+                sink(info, samples)
+            });
+            match result {
+                Err(_) if !fed => warn!("FLAC reader failed, fallback to generic Audio reader"),
+                result => return result,
+            }
+        } else {
+            warn!("Fallback to generic Audio reader");
+        }
+        // only fallback when symphonia can not even open the file,
+        // otherwise sink would get some samples twice
+        match Self::open_generic_file(path.as_ref()) {
+            Ok(stream) => Self::stream_generic(stream, sink),
+            Err(e) => {
+                warn!("Generic Audio reader failed ({e}), fallback to ffmpeg");
+                // symphonia has no DSD decoder, ffmpeg may or may not have one
+                match Self::open_ffmpeg(&path) {
+                    Ok(stream) => Self::stream_ffmpeg(stream, sink),
+                    Err(_) if is_dsd(path.as_ref()) => Err(AudioError::Dsd.into()),
+                    Err(e) => Err(e.into()),
+                }
+            }
         }
     }
 
-    fn from_flac_file<P: AsRef<Path>>(path: P) -> Self {
-        let mut r = claxon::FlacReader::open(path).unwrap();
+    fn stream_flac_file<P, F, E>(path: P, mut sink: F) -> Result<AudioInfo, E>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&AudioInfo, AudioRef) -> Result<ControlFlow<()>, E>,
+        E: From<AudioError>,
+    {
+        let mut r = claxon::FlacReader::open(path).map_err(AudioError::from)?;
         let streaminfo = r.streaminfo();
         let bits = streaminfo.bits_per_sample as u8;
-        let audio = match bits {
-            0..=16 => Audio::S16(r.samples().map(|f| f.unwrap() as i16).collect()),
-            17..=32 => Audio::S32(r.samples().map(|f| f.unwrap() << (32 - bits)).collect()),
-            _ => panic!(""),
-        };
-        Audi {
-            audio,
+        let info = AudioInfo {
             channels: streaminfo.channels,
             sample_rate: streaminfo.sample_rate,
             bits,
-        }
+        };
+        let chunk = CHUNK_FRAMES * info.channels as usize;
+
+        // Samples are left-aligned to full scale of i16/i32, `<<` keeps the sign
+        // (e.g. 24 bit -1 becomes -256). EbuR128 divides by 2^15 resp. 2^31, so full
+        // scale is 1.0 whatever the bit depth, and 16/24 bit versions of the same audio
+        // measure the same loudness. Bit depth 0 means broken streaminfo.
+        match bits {
+            1..=16 => {
+                let mut buf = Vec::with_capacity(chunk);
+                for sample in r.samples() {
+                    buf.push((sample.map_err(AudioError::from)? << (16 - bits)) as i16);
+                    if buf.len() == chunk {
+                        if sink(&info, AudioRef::from_i16(&buf))?.is_break() {
+                            return Ok(info);
+                        }
+                        buf.clear();
+                    }
+                }
+                if !buf.is_empty() {
+                    let _ = sink(&info, AudioRef::from_i16(&buf))?;
+                }
+            }
+            17..=32 => {
+                let mut buf = Vec::with_capacity(chunk);
+                for sample in r.samples() {
+                    buf.push(sample.map_err(AudioError::from)? << (32 - bits));
+                    if buf.len() == chunk {
+                        if sink(&info, AudioRef::from_i32(&buf))?.is_break() {
+                            return Ok(info);
+                        }
+                        buf.clear();
+                    }
+                }
+                if !buf.is_empty() {
+                    let _ = sink(&info, AudioRef::from_i32(&buf))?;
+                }
+            }
+            _ => return Err(AudioError::BitDepth(streaminfo.bits_per_sample).into()),
+        };
+        Ok(info)
     }
 
-    fn from_generic_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+    fn open_generic_file<P: AsRef<Path>>(path: P) -> Result<GenericStream, AudioError> {
         // Open the media source.
         let file = std::fs::File::open(path.as_ref())?;
 
-        // Create the media source stream.
-        let mss = MediaSourceStream::new(Box::new(file), Default::default());
-
         // Create a probe hint using the file's extension. [Optional]
+        // Registered extensions are lowercase, so `.MP3` must be hinted as `mp3`.
         let mut hint = Hint::new();
-        hint.with_extension(path.as_ref().extension().unwrap().to_str().unwrap());
+        if let Some(ext) = extension(path.as_ref()) {
+            hint.with_extension(&ext);
+        }
+
+        Self::open_generic(Box::new(file), &hint)
+    }
+
+    fn open_generic(
+        source: Box<dyn MediaSource>,
+        hint: &Hint,
+    ) -> Result<GenericStream, AudioError> {
+        // Create the media source stream.
+        let mss = MediaSourceStream::new(source, Default::default());
 
         // Use the default options for metadata and format readers.
         let meta_opts: MetadataOptions = Default::default();
         let fmt_opts: FormatOptions = Default::default();
 
         // Probe the media source.
-        let mut probed =
-            symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-        let track = probed.format.default_track().unwrap();
+        let probed = symphonia::default::get_probe().format(hint, mss, &fmt_opts, &meta_opts)?;
+        // Matroska/WebM and MP4 may start with a video track
+        let decodable = |track: &&Track| track.codec_params.codec != CODEC_TYPE_NULL;
+        let track = probed
+            .format
+            .default_track()
+            .filter(decodable)
+            .or_else(|| probed.format.tracks().iter().find(decodable))
+            .ok_or(AudioError::NoTrack)?;
         let track_id = track.id;
         let decode_opts = DecoderOptions { verify: true };
 
         // Create a decoder for the track.
-        let mut decoder =
-            symphonia::default::get_codecs().make(&track.codec_params, &decode_opts)?;
-        let mut sample_buf = None;
-        let mut audio: Option<Audio> = None;
+        let decoder = symphonia::default::get_codecs().make(&track.codec_params, &decode_opts)?;
 
-        while let Ok(packet) = probed.format.next_packet() {
+        Ok(GenericStream {
+            format: probed.format,
+            decoder,
+            track_id,
+        })
+    }
+
+    fn stream_generic<F, E>(mut stream: GenericStream, mut sink: F) -> Result<AudioInfo, E>
+    where
+        F: FnMut(&AudioInfo, AudioRef) -> Result<ControlFlow<()>, E>,
+        E: From<AudioError>,
+    {
+        let bits = stream.decoder.codec_params().bits_per_sample.unwrap_or(0) as u8;
+        let mut sample_buf = None;
+        let mut info = None;
+        let mut index = 0_u64;
+
+        loop {
+            let packet = match stream.format.next_packet() {
+                Ok(packet) => packet,
+                // symphonia ends every stream like this
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                }
+                // anything else would silently cut the measurement short
+                Err(e) => return Err(AudioError::from(e).into()),
+            };
             // If the packet does not belong to the selected track, skip it.
-            if packet.track_id() != track_id {
+            if packet.track_id() != stream.track_id {
                 continue;
             }
-
-            // Decode the packet into audio samples, ignoring any decode errors.
-            let audio_buf = decoder.decode(&packet)?;
+            index += 1;
+
+            // Decode the packet into audio samples. A corrupt packet is skipped,
+            // later packets usually decode fine.
+            let audio_buf = match stream.decoder.decode(&packet) {
+                Ok(audio_buf) => audio_buf,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    warn!("Skipping packet {index}: {e}");
+                    continue;
+                }
+                Err(SymphoniaError::IoError(e)) => {
+                    warn!("Skipping packet {index}: {e}");
+                    continue;
+                }
+                Err(e) => return Err(AudioError::from(e).into()),
+            };
             // If this is the *first* decoded packet, create a sample buffer matching the
             // decoded audio buffer format.
             if sample_buf.is_none() {
@@ -181,44 +500,133 @@ impl Audi {
                 // Get the capacity of the decoded buffer. Note: This is capacity, not length!
                 let duration = audio_buf.capacity() as u64;
 
-                // Create the f32 sample buffer.
+                info = Some(AudioInfo {
+                    channels: spec.channels.count() as u32,
+                    sample_rate: spec.rate,
+                    bits,
+                });
                 sample_buf = Some(AudioSampleBuffer::new(&audio_buf, duration, spec));
             }
 
             // Copy the decoded audio buffer into the sample buffer in an interleaved format.
-            if let Some(buf) = &mut sample_buf {
+            if let (Some(buf), Some(info)) = (&mut sample_buf, &info) {
+                // interleaved samples would be misread from here on
+                let channels = audio_buf.spec().channels.count() as u32;
+                if channels != info.channels {
+                    return Err(AudioError::ChannelMismatch(info.channels, channels).into());
+                }
                 buf.copy_interleaved_ref(audio_buf);
 
                 // The samples may now be access via the `samples()` function.
-                if let Some(s) = &mut audio {
-                    s.extend_from_slice(buf.samples());
-                } else {
-                    audio = Some(buf.samples().to_owned())
+                if sink(info, buf.samples())?.is_break() {
+                    break;
                 }
             }
         }
 
-        let streaminfo = &probed.format.default_track().unwrap().codec_params;
+        Ok(info.ok_or(AudioError::MissingInfo("audio"))?)
+    }
 
-        Ok(Audi {
-            audio: audio.unwrap(),
-            channels: streaminfo.channels.unwrap().count() as u32,
-            sample_rate: streaminfo.sample_rate.unwrap(),
-            bits: streaminfo.bits_per_sample.unwrap_or(0) as u8,
+    fn open_ffmpeg<P: AsRef<Path>>(path: P) -> Result<FfmpegStream, AudioError> {
+        ffmpeg::init()?;
+        ffmpeg::log::set_level(ffmpeg::log::Level::Quiet);
+        let format = ffmpeg::format::input(&path)?;
+        let stream = format
+            .streams()
+            .best(ffmpeg::media::Type::Audio)
+            .ok_or(AudioError::NoTrack)?;
+        let track_index = stream.index();
+        let decoder = ffmpeg::codec::Context::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+        let channels = decoder.channels() as u32;
+        let sample_rate = decoder.rate();
+        let bits = (decoder.format().bytes() * 8) as u8;
+
+        // some decoders leave the layout unset, the resampler needs one
+        let layout = match decoder.channel_layout() {
+            layout if layout.is_empty() => ffmpeg::ChannelLayout::default(channels as i32),
+            layout => layout,
+        };
+        // convert whatever the decoder produces to interleaved f32
+        let resampler = ffmpeg::software::resampling::Context::get(
+            decoder.format(),
+            layout,
+            sample_rate,
+            ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed),
+            layout,
+            sample_rate,
+        )?;
+
+        Ok(FfmpegStream {
+            format,
+            decoder,
+            resampler,
+            track_index,
+            info: AudioInfo {
+                channels,
+                sample_rate,
+                bits,
+            },
         })
     }
 
-    fn from_ffmpeg<P: AsRef<Path>>(path: P) -> Self {
-        ffmpeg::init().unwrap();
-        ffmpeg::log::set_level(ffmpeg::log::Level::Quiet);
-        let mut format = ffmpeg::format::input(&path).unwrap();
-        todo!();
-        /*Audi {
-            audio: audio.unwrap(),
-            channels: streaminfo.channels.unwrap().count() as u32,
-            sample_rate: streaminfo.sample_rate.unwrap(),
-            bits: streaminfo.bits_per_sample.unwrap_or(0) as u8,
-        }*/
+    fn stream_ffmpeg<F, E>(mut stream: FfmpegStream, mut sink: F) -> Result<AudioInfo, E>
+    where
+        F: FnMut(&AudioInfo, AudioRef) -> Result<ControlFlow<()>, E>,
+        E: From<AudioError>,
+    {
+        let info = stream.info;
+        let mut fed = false;
+        // one converted frame, reused
+        let mut samples = Vec::new();
+        // pass every frame the decoder has ready to sink
+        let mut receive = |decoder: &mut ffmpeg::decoder::Audio,
+                           resampler: &mut ffmpeg::software::resampling::Context|
+         -> Result<ControlFlow<()>, E> {
+            let mut frame = ffmpeg::frame::Audio::empty();
+            while decoder.receive_frame(&mut frame).is_ok() {
+                let mut converted = ffmpeg::frame::Audio::empty();
+                resampler
+                    .run(&frame, &mut converted)
+                    .map_err(AudioError::from)?;
+                // the plane is padded, only the first samples * channels are audio
+                let len = converted.samples() * info.channels as usize * 4;
+                samples.clear();
+                samples.extend(
+                    converted.data(0)[..len]
+                        .chunks_exact(4)
+                        .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])),
+                );
+                if samples.is_empty() {
+                    continue;
+                }
+                fed = true;
+                if sink(&info, AudioRef::from_f32(&samples))?.is_break() {
+                    return Ok(ControlFlow::Break(()));
+                }
+            }
+            Ok(ControlFlow::Continue(()))
+        };
+        for (packet_stream, packet) in stream.format.packets() {
+            if packet_stream.index() != stream.track_index {
+                continue;
+            }
+            if let Err(e) = stream.decoder.send_packet(&packet) {
+                warn!("Skipping packet: {e}");
+                continue;
+            }
+            if receive(&mut stream.decoder, &mut stream.resampler)?.is_break() {
+                return Ok(info);
+            }
+        }
+        stream.decoder.send_eof().map_err(AudioError::from)?;
+        let _ = receive(&mut stream.decoder, &mut stream.resampler)?;
+
+        if !fed {
+            return Err(AudioError::MissingInfo("audio").into());
+        }
+        Ok(info)
     }
 }
 
@@ -231,6 +639,9 @@ enum AudioSampleBuffer {
 
 impl AudioSampleBuffer {
     /// create new based on audiobufferref type
+    ///
+    /// 8 bit audio is widened to i16 by symphonia, exactly: S8 is shifted by 8 bits and U8 is
+    /// centered first (`(s - 128) << 8`), so loudness and peaks match a 16 bit copy.
     fn new(audio_buf: &AudioBufferRef, duration: Duration, spec: SignalSpec) -> Self {
         match audio_buf {
             AudioBufferRef::U8(_) => Self::S16(SampleBuffer::new(duration, spec)),
@@ -262,7 +673,7 @@ impl AudioSampleBuffer {
     }
 
     /// Gets an immutable slice of all written samples.
-    pub fn samples(&self) -> AudioRef {
+    pub fn samples(&self) -> AudioRef<'_> {
         match self {
             Self::S16(s) => AudioRef::from_i16(s.samples()),
             Self::S32(s) => AudioRef::from_i32(s.samples()),
@@ -271,3 +682,63 @@ impl AudioSampleBuffer {
         }
     }
 }
+
+/// Whether `path` starts like a DSF (`DSD `) or DSDIFF (`FRM8`) file
+fn is_dsd(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && (&magic == b"DSD " || &magic == b"FRM8")
+}
+
+/// Lowercase extension of `path`
+pub(crate) fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downmix_layouts() {
+        for channels in 3..=8 {
+            let coefficients = downmix_coefficients(channels).unwrap();
+            assert_eq!(coefficients.len(), channels as usize);
+            // front left and right pass through
+            assert_eq!(coefficients[..2], [(1.0, 0.0), (0.0, 1.0)]);
+        }
+        for channels in [0, 1, 2, 9] {
+            assert!(!can_downmix(channels));
+        }
+        // LFE is dropped
+        assert_eq!(downmix_coefficients(6).unwrap()[3], (0.0, 0.0));
+    }
+
+    #[test]
+    fn downmix_5_1_to_stereo() {
+        let c = std::f64::consts::FRAC_1_SQRT_2;
+        // L, R, C, LFE, Ls, Rs
+        let frames: [i16; 18] = [
+            16384, 0, 0, 0, 0, 0, // left only
+            0, 0, 16384, 32767, 0, 0, // center and LFE
+            0, 8192, 0, 0, 16384, -16384, // right and surrounds
+        ];
+        let stereo = AudioRef::from_i16(&frames).downmix_stereo(6).unwrap();
+        let expected = [0.5, 0.0, 0.5 * c, 0.5 * c, 0.5 * c, 0.25 - 0.5 * c];
+        assert_eq!(stereo.len(), expected.len());
+        for (got, want) in stereo.iter().zip(expected) {
+            assert!((got - want).abs() < 1e-12, "{stereo:?}");
+        }
+        // every sample type gives the same result
+        let floats: Vec<f32> = frames.iter().map(|&s| s as f32 / 32768.0).collect();
+        assert_eq!(
+            AudioRef::from_f32(&floats).downmix_stereo(6).unwrap(),
+            stereo
+        );
+        assert_eq!(AudioRef::from_i16(&frames).downmix_stereo(2), None);
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::warn;
+use serde_json::{json, Map, Value};
+
+use loudgainer::replay_gain::{ReplayGain, ScanOptions};
+
+/// Scan results on disk (JSON), keyed by canonical path
+///
+/// An entry is valid while size and mtime of the file are unchanged (and it was scanned with
+/// the same --downmix). Measured values are stored rather than gains, so entries still apply
+/// with another reference or pre-gain.
+pub struct Cache {
+    path: PathBuf,
+    entries: Map<String, Value>,
+}
+
+impl Cache {
+    /// Load cache from `path`; a missing or broken cache file starts empty
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let entries = match fs::read(path.as_ref()) {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(Value::Object(entries)) => entries,
+                _ => {
+                    warn!("Ignoring invalid cache {}", path.as_ref().display());
+                    Map::new()
+                }
+            },
+            Err(_) => Map::new(),
+        };
+        Self {
+            path: path.as_ref().to_owned(),
+            entries,
+        }
+    }
+
+    /// Cached result for unchanged `file`
+    pub fn get(&self, file: &str, opts: &ScanOptions) -> Option<ReplayGain> {
+        let (key, stamp) = stamp(file)?;
+        let entry = self.entries.get(&key)?;
+        if entry["stamp"] != stamp || entry["downmix"].as_bool() != Some(opts.downmix) {
+            return None;
+        }
+        let true_peak = if opts.true_peak {
+            Some(entry["true_peak"].as_f64()?)
+        } else {
+            None
+        };
+        let rg = ReplayGain {
+            duration: entry["duration"].as_f64()?,
+            ..ReplayGain::from_loudness(
+                entry["loudness"].as_f64()?,
+                entry["range"].as_f64()?,
+                entry["sample_peak"].as_f64()?,
+                true_peak,
+                opts,
+            )
+        };
+        if opts.detailed {
+            return Some(ReplayGain {
+                momentary_max: Some(entry["momentary_max"].as_f64()?),
+                short_term_max: Some(entry["short_term_max"].as_f64()?),
+                ..rg
+            });
+        }
+        Some(rg)
+    }
+
+    /// Remember unclipped scan result of `file`
+    pub fn insert(&mut self, file: &str, rg: &ReplayGain, opts: &ScanOptions) {
+        if let Some((key, stamp)) = stamp(file) {
+            self.entries.insert(
+                key,
+                json!({
+                    "stamp": stamp,
+                    "loudness": rg.loudness,
+                    "range": rg.loudness_range,
+                    "sample_peak": rg.sample_peak,
+                    "true_peak": rg.true_peak,
+                    "momentary_max": rg.momentary_max,
+                    "short_term_max": rg.short_term_max,
+                    "duration": rg.duration,
+                    "downmix": opts.downmix,
+                }),
+            );
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        fs::write(&self.path, serde_json::to_vec(&self.entries)?)
+    }
+}
+
+/// Canonical path and (size, mtime) of `file`
+fn stamp(file: &str) -> Option<(String, Value)> {
+    let path = fs::canonicalize(file).ok()?;
+    let meta = fs::metadata(&path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((
+        path.to_string_lossy().into_owned(),
+        json!([meta.len(), mtime.as_secs(), mtime.subsec_nanos()]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn entries_are_invalidated_by_size_mtime_and_downmix() {
+        let dir = std::env::temp_dir();
+        let file = dir.join(format!("loudgainer-{}-cached.flac", std::process::id()));
+        let cache_file = dir.join(format!("loudgainer-{}-cache.json", std::process::id()));
+        fs::write(&file, b"audio").unwrap();
+        let file_name = file.to_string_lossy().into_owned();
+        let opts = ScanOptions::default();
+        let rg = ReplayGain {
+            duration: 2.5,
+            ..ReplayGain::from_loudness(-11.0, 4.0, 0.9, Some(0.95), &opts)
+        };
+
+        let mut cache = Cache::load(&cache_file);
+        assert_eq!(cache.get(&file_name, &opts), None);
+        cache.insert(&file_name, &rg, &opts);
+        cache.save().unwrap();
+        let cache = Cache::load(&cache_file);
+        assert_eq!(cache.get(&file_name, &opts), Some(rg));
+        // measured values are kept, the gain follows the reference
+        let other = ScanOptions {
+            reference: -23.0,
+            ..Default::default()
+        };
+        assert_eq!(cache.get(&file_name, &other).unwrap().gain, -12.0);
+        let downmix = ScanOptions {
+            downmix: true,
+            ..Default::default()
+        };
+        assert_eq!(cache.get(&file_name, &downmix), None);
+
+        let mtime = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        assert_eq!(cache.get(&file_name, &opts), None);
+        let mut cache = cache;
+        cache.insert(&file_name, &rg, &opts);
+        assert_eq!(cache.get(&file_name, &opts), Some(rg));
+        fs::write(&file, b"other").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        // same size and mtime, so the change goes unnoticed; a longer file does not
+        assert_eq!(cache.get(&file_name, &opts), Some(rg));
+        fs::write(&file, b"longer audio").unwrap();
+        assert_eq!(cache.get(&file_name, &opts), None);
+
+        fs::remove_file(&file).unwrap();
+        fs::remove_file(&cache_file).unwrap();
+    }
+
+    #[test]
+    fn broken_cache_starts_empty() {
+        let path =
+            std::env::temp_dir().join(format!("loudgainer-{}-broken.json", std::process::id()));
+        fs::write(&path, b"[1, 2").unwrap();
+        let cache = Cache::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,13 @@
+use crate::audio::AudioError;
+use crate::tagger::TagError;
+
+/// Errors of scanning files, computing gains and tagging
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Audio(#[from] AudioError),
+    #[error("Loudness measurement error: {0}")]
+    Loudness(#[from] ebur128::Error),
+    #[error(transparent)]
+    Tag(#[from] TagError),
+}
@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+/// Extensions of files that can be scanned, what directories are searched for by default
+pub const EXTENSIONS: [&str; 20] = [
+    "flac", "ogg", "oga", "spx", "opus", "mp2", "mp3", "mp4", "m4a", "m4b", "asf", "wma", "wv",
+    "ape", "wav", "aiff", "aif", "snd", "mka", "webm",
+];
+
+/// Replace directories in `inputs` with files found in them (recursively) that have one of
+/// `extensions` (lowercase). Other inputs are kept as they are.
+pub fn expand(inputs: &[String], extensions: &[String]) -> Vec<String> {
+    let mut visited = HashSet::new();
+    let mut files = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            walk(path, extensions, &mut visited, &mut files);
+        } else {
+            files.push(input.clone());
+        }
+    }
+    files
+}
+
+fn walk(
+    dir: &Path,
+    extensions: &[String],
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<String>,
+) {
+    // guard against symlink loops
+    match dir.canonicalize() {
+        Ok(canonical) => {
+            if !visited.insert(canonical) {
+                warn!("Skipping already visited directory {}", dir.display());
+                return;
+            }
+        }
+        Err(e) => {
+            warn!("Skipping {}: {e}", dir.display());
+            return;
+        }
+    }
+
+    let mut entries: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok().map(|e| e.path())).collect(),
+        Err(e) => {
+            warn!("Skipping {}: {e}", dir.display());
+            return;
+        }
+    };
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            walk(&path, extensions, visited, files);
+        } else if has_extension(&path, extensions) {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// Has the file one of `extensions` (lowercase, compared case-insensitively)?
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| extensions.contains(&ext.to_ascii_lowercase()))
+        .unwrap_or(false)
+}
+
+/// Files listed in an M3U/M3U8 playlist, in order
+///
+/// Comments (`#EXTINF` etc.) and URLs are skipped; relative paths are resolved against the
+/// directory of the playlist.
+pub fn read_playlist(playlist: &str) -> io::Result<Vec<String>> {
+    let bytes = fs::read(playlist)?;
+    // M3U8 is UTF-8 (maybe with BOM), plain M3U is usually close enough
+    let text = String::from_utf8_lossy(&bytes);
+    let base = Path::new(playlist)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut files = Vec::new();
+    for line in text.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.contains("://") {
+            warn!("Skipping {line} in {playlist}: not a local file");
+            continue;
+        }
+        files.push(base.join(line).to_string_lossy().into_owned());
+    }
+    Ok(files)
+}
+
+/// Group files by their parent directory, in order of first occurrence
+pub fn group_by_dir(files: &[String]) -> Vec<Vec<String>> {
+    let mut groups: Vec<(Option<&Path>, Vec<String>)> = Vec::new();
+    for file in files {
+        let dir = Path::new(file).parent();
+        match groups.iter_mut().find(|(d, _)| *d == dir) {
+            Some((_, group)) => group.push(file.clone()),
+            None => groups.push((dir, vec![file.clone()])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory `name` in the temp dir
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loudgainer-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn touch(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+    }
+
+    fn names(dir: &Path, files: &[String]) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| {
+                let f = Path::new(f).strip_prefix(dir).unwrap();
+                f.to_string_lossy().replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expand_walks_sorted_and_survives_symlink_loop() {
+        let dir = scratch("walk");
+        touch(
+            &dir,
+            &["b/2.flac", "b/1.mp3", "a/1.flac", "a/cover.jpg", "top.opus"],
+        );
+        // b/loop -> parent, would recurse forever
+        std::os::unix::fs::symlink(&dir, dir.join("b/loop")).unwrap();
+        let extensions: Vec<String> = EXTENSIONS.iter().map(|e| e.to_string()).collect();
+        let files = expand(&[dir.to_string_lossy().into_owned()], &extensions);
+        assert_eq!(
+            names(&dir, &files),
+            ["a/1.flac", "b/1.mp3", "b/2.flac", "top.opus"]
+        );
+        let albums: Vec<usize> = group_by_dir(&files).iter().map(Vec::len).collect();
+        assert_eq!(albums, [1, 2, 1]);
+        // explicit files are kept, whatever their extension
+        let explicit = dir.join("a/cover.jpg").to_string_lossy().into_owned();
+        assert_eq!(
+            expand(std::slice::from_ref(&explicit), &extensions),
+            [explicit]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_playlist_skips_comments_and_resolves_relative_paths() {
+        let dir = scratch("playlist");
+        let playlist = dir.join("list.m3u8");
+        let absolute = dir.join("abs.flac").to_string_lossy().into_owned();
+        fs::write(
+            &playlist,
+            format!(
+                "\u{feff}#EXTM3U\n#EXTINF:123,Artist - One\ndisc 1/one.flac\n\n  two.mp3  \r\nhttp://example.com/stream.mp3\n{absolute}\n"
+            ),
+        )
+        .unwrap();
+        let files = read_playlist(&playlist.to_string_lossy()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let expected: Vec<String> = [dir.join("disc 1/one.flac"), dir.join("two.mp3")]
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .chain([absolute])
+            .collect();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn extensions_filter_case_insensitively() {
+        let dir = scratch("extensions");
+        touch(
+            &dir,
+            &["a.flac", "b.FLAC", "c.Mp3", "d.mp3", "e.Opus", "noext"],
+        );
+        let only = |extensions: &[&str]| {
+            let extensions: Vec<String> = extensions.iter().map(|e| e.to_string()).collect();
+            names(
+                &dir,
+                &expand(&[dir.to_string_lossy().into_owned()], &extensions),
+            )
+        };
+        assert_eq!(only(&["flac"]), ["a.flac", "b.FLAC"]);
+        assert_eq!(only(&["mp3", "opus"]), ["c.Mp3", "d.mp3", "e.Opus"]);
+        assert!(only(&["wav"]).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
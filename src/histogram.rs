@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+/// Longest bar of [Histogram::lines]
+const BAR_WIDTH: usize = 40;
+
+/// Track gains counted in 1 dB (LU) buckets, for an overview of a whole library
+#[derive(Default)]
+pub struct Histogram {
+    /// lower bound -> count
+    buckets: BTreeMap<i64, usize>,
+}
+
+impl Histogram {
+    pub fn add(&mut self, gain: f64) {
+        if gain.is_finite() {
+            *self.buckets.entry(gain.floor() as i64).or_default() += 1;
+        }
+    }
+
+    /// (lower bound, count) of every bucket from the lowest to the highest gain, empty
+    /// buckets in between included
+    pub fn buckets(&self) -> Vec<(i64, usize)> {
+        match (self.buckets.keys().next(), self.buckets.keys().next_back()) {
+            (Some(&low), Some(&high)) => (low..=high)
+                .map(|bucket| (bucket, self.buckets.get(&bucket).copied().unwrap_or(0)))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// One line per bucket: range, count and a bar scaled to the largest bucket
+    pub fn lines(&self, unit: &str) -> Vec<String> {
+        let buckets = self.buckets();
+        let max = buckets.iter().map(|&(_, count)| count).max().unwrap_or(0);
+        buckets
+            .into_iter()
+            .map(|(low, count)| {
+                // round up, so no bucket with tracks looks empty
+                let bar = (count * BAR_WIDTH).div_ceil(max);
+                let line = format!(
+                    "{low:>4} .. {:>4} {unit} {count:>6} {}",
+                    low + 1,
+                    "#".repeat(bar)
+                );
+                line.trim_end().to_owned()
+            })
+            .collect()
+    }
+
+    /// Buckets as `[{"from": .., "to": .., "count": ..}, ..]`
+    pub fn json(&self) -> Value {
+        self.buckets()
+            .into_iter()
+            .map(|(low, count)| json!({ "from": low, "to": low + 1, "count": count }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_1_db_wide_and_contiguous() {
+        let mut histogram = Histogram::default();
+        for gain in [-7.5, -7.0, -6.99, -4.2, f64::NAN, f64::INFINITY] {
+            histogram.add(gain);
+        }
+        // -6.99 floors to -7, empty -6 and -5 are filled in, non-finite gains are dropped
+        assert_eq!(
+            histogram.buckets(),
+            vec![(-8, 1), (-7, 2), (-6, 0), (-5, 1)]
+        );
+    }
+
+    #[test]
+    fn bars_round_up_to_the_largest_bucket() {
+        let mut histogram = Histogram::default();
+        for gain in [0.5; 80].into_iter().chain([1.5]) {
+            histogram.add(gain);
+        }
+        let lines = histogram.lines("dB");
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&"#".repeat(BAR_WIDTH)));
+        // 1 of 80 tracks still gets a visible bar
+        assert!(lines[1].ends_with(" 1 #"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn empty_histogram_has_no_lines() {
+        assert!(Histogram::default().lines("dB").is_empty());
+        assert_eq!(Histogram::default().json(), serde_json::json!([]));
+    }
+}
@@ -0,0 +1,22 @@
+//! Compute EBU R128 loudness and ReplayGain 2.0 values, and write them as tags.
+//!
+//! Scan a single file and read its gain:
+//!
+//! ```no_run
+//! let (rg, _) = loudgainer::track_rg("song.flac", -18.0, 0.0).unwrap();
+//! println!("{:.2} dB", rg.gain);
+//! ```
+pub mod audio;
+mod error;
+pub mod mp4;
+pub mod opus;
+pub mod replay_gain;
+pub mod tagger;
+
+pub use audio::Audi;
+pub use error::Error;
+pub use replay_gain::{
+    album_rg, album_rg_continuous, album_rg_iter, dbtp_to_lufs, lufs_to_dbtp, lufs_to_rg, track_rg,
+    track_rg_from_audio, ClipMode, ReplayGain, ScanOptions,
+};
+pub use tagger::{delete_tags, write_tags, TagError, WriteOptions};
@@ -1,80 +1,695 @@
+use std::io;
+use std::path::Path;
+use std::process::exit;
+
 use ebur128::EbuR128;
-use log::debug;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+
+use loudgainer::replay_gain::{
+    album_formats, album_rg, album_rg_continuous, compact_state, other_unit, track_rg_streaming,
+    ReplayGain, ScanOptions,
+};
+use loudgainer::tagger;
 
-use crate::replay_gain::{album_rg, track_rg, ReplayGain};
+/// Some files could not be scanned (or their album was refused)
+const EXIT_FAILED: i32 = 1;
+// 2 is used by gumdrop for invalid arguments
+/// Some track or album will clip, and clipping was not prevented
+const EXIT_CLIPPED: i32 = 3;
+/// --check found missing or wrong tags
+const EXIT_MISMATCH: i32 = 4;
 
-mod audio;
+mod cache;
+mod files;
+mod histogram;
 mod options;
-mod replay_gain;
-mod tagger;
+mod progress;
+mod sidecar;
 
 fn main() {
     let opts = options::parse_arguments();
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let log_level = if opts.quiet { "error" } else { "warn" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
     debug!("{:#?}", opts);
 
+    // deleting tags does not need any scanning
+    if opts.mode == options::Mode::Delete {
+        let mut unwritten = Vec::new();
+        for path in &opts.files {
+            if is_stdin(path) {
+                continue;
+            } else if !may_write(&opts, path) {
+                unwritten.push(path);
+            } else if opts.dry_run {
+                eprintln!("Would delete ReplayGain tags from {path}");
+            } else if let Err(e) = tagger::delete_tags(path, opts.id3v2version, opts.tag_format) {
+                error!("Failed to delete tags of {path}: {e}");
+                unwritten.push(path);
+            }
+        }
+        if !unwritten.is_empty() {
+            report_unwritten(&unwritten);
+            exit(EXIT_FAILED);
+        }
+        return;
+    }
+
     match opts.output {
-        options::OutputMode::Human => {println!("Scanning all files.")},
+        options::OutputMode::Human => if !opts.quiet && opts.normalize_to.is_none() { println!("Scanning all files.") },
         options::OutputMode::Old => println!("File\tMP3 gain\tdB gain\tMax Amplitude\tMax global_gain\tMin global_gain"),
-        options::OutputMode::New => println!("File\tLoudness\tRange\tTrue_Peak\tTrue_Peak_dBTP\tReference\tWill_clip\tClip_prevent\tGain\tNew_Peak\tNew_Peak_dBTP"),
+        options::OutputMode::New if opts.true_peak => println!("File\tLoudness\tRange\tTrue_Peak\tTrue_Peak_dBTP\tReference\tWill_clip\tClip_prevent\tGain\tNew_Peak\tNew_Peak_dBTP{}{}", if opts.detailed { "\tMomentary_Max\tShort_Term_Max\tEffective_Loudness" } else { "" }, other_unit_header(&opts)),
+        options::OutputMode::New => println!("File\tLoudness\tRange\tSample_Peak\tSample_Peak_dBFS\tReference\tWill_clip\tClip_prevent\tGain\tNew_Peak\tNew_Peak_dBFS{}{}", if opts.detailed { "\tMomentary_Max\tShort_Term_Max\tEffective_Loudness" } else { "" }, other_unit_header(&opts)),
+        options::OutputMode::Json => { /* everything is printed at the end */ }
     };
 
-    let tracks: Vec<(ReplayGain, EbuR128)> = opts
-        .files
-        .iter()
-        .map(|x| track_rg(x, opts.pre_gain).unwrap())
-        .collect();
-
-    let album: Option<ReplayGain> = if opts.do_album {
-        Some(album_rg(&tracks, opts.pre_gain).unwrap().clipper(
-            opts.max_true_peak_level,
-            opts.warn_clip,
-            opts.clip_prevention,
-        ))
+    if let Some(threads) = opts.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+
+    if let Some(seconds) = opts.preview_seconds {
+        warn!("Only the first {seconds} s of every file are scanned, results are approximate");
+    }
+    if let Some((start, end)) = opts.window {
+        warn!("Only {start} s to {end} s of every file are scanned, results are approximate");
+    }
+
+    let albums = if opts.per_directory_album {
+        files::group_by_dir(&opts.files)
+    } else {
+        vec![opts.files.clone()]
+    };
+
+    let mut cache = opts.cache.as_ref().map(cache::Cache::load);
+    let progress = progress::Progress::new(opts.files.len(), !opts.quiet);
+    let mut json_tracks = Vec::new();
+    let mut json_albums = Vec::new();
+    let mut failed = Vec::new();
+    let mut clipped = false;
+    let mut mismatched = false;
+    let mut unwritten = Vec::new();
+    let mut summary = Summary::default();
+    let mut histogram = histogram::Histogram::default();
+    for files in &albums {
+        let result = process_album(&opts, files, &progress, cache.as_mut());
+        clipped |= result.clips();
+        mismatched |= !result.mismatched.is_empty();
+        unwritten.extend(result.unwritten.iter().copied());
+        failed.extend(result.failed.iter().cloned());
+        summary.add(&result);
+        for (_, rg) in &result.tracks {
+            histogram.add(rg.gain);
+        }
+        if opts.output == options::OutputMode::Json {
+            for (path, rg) in &result.tracks {
+                let mut track = rg.json();
+                track["file"] = path.as_str().into();
+                add_units(&opts, &mut track);
+                json_tracks.push(track);
+            }
+            if let Some(album) = result.album {
+                let mut album = album.json();
+                add_units(&opts, &mut album);
+                album["files"] = result
+                    .tracks
+                    .iter()
+                    .map(|(path, _)| path.as_str())
+                    .collect();
+                json_albums.push(album);
+            }
+        }
+    }
+
+    if let Some(cache) = cache {
+        if let Err(e) = cache.save() {
+            warn!("Failed to save cache: {e}");
+        }
+    }
+
+    if opts.output == options::OutputMode::Human && !opts.quiet && opts.normalize_to.is_none() {
+        summary.display(&opts.output_unit, opts.precision);
+    }
+
+    if opts.histogram {
+        let mut lines = histogram.lines(&opts.output_unit);
+        if !lines.is_empty() {
+            lines.insert(0, String::from("Track gains:"));
+        }
+        match opts.output {
+            options::OutputMode::Json => {}
+            // keep list output parseable
+            options::OutputMode::Human if opts.normalize_to.is_none() => {
+                lines.iter().for_each(|line| println!("{line}"))
+            }
+            _ => lines.iter().for_each(|line| eprintln!("{line}")),
+        }
+    }
+
+    if opts.output == options::OutputMode::Json {
+        let mut document = serde_json::json!({ "tracks": json_tracks, "albums": json_albums });
+        if opts.histogram {
+            document["histogram"] = histogram.json();
+        }
+        document["failed"] = failed
+            .iter()
+            .map(|(path, reason)| serde_json::json!({ "file": path, "error": reason }))
+            .collect();
+        println!("{document:#}");
+    } else if !failed.is_empty() {
+        report_failed(&opts, &failed);
+    }
+
+    if !unwritten.is_empty() {
+        report_unwritten(&unwritten);
+    }
+
+    if !failed.is_empty() || !unwritten.is_empty() {
+        exit(EXIT_FAILED);
+    } else if mismatched {
+        exit(EXIT_MISMATCH);
+    } else if clipped {
+        exit(EXIT_CLIPPED);
+    }
+}
+
+/// List files that could not be processed and why, after the summary
+fn report_failed(opts: &options::Opts, failed: &[(&String, String)]) {
+    let lines = std::iter::once(format!("Failed ({}):", failed.len())).chain(
+        failed
+            .iter()
+            .map(|(path, reason)| format!("  {path}: {reason}")),
+    );
+    // keep list output parseable
+    if opts.output == options::OutputMode::Human && opts.normalize_to.is_none() && !opts.quiet {
+        lines.for_each(|line| println!("{line}"));
+    } else {
+        lines.for_each(|line| eprintln!("{line}"));
+    }
+}
+
+/// List files whose tags could not be written, after everything else
+fn report_unwritten(files: &[&String]) {
+    eprintln!("Failed to write tags of {} files:", files.len());
+    for file in files {
+        eprintln!("  {file}");
+    }
+}
+
+/// Everything of one album that is reported at the end
+struct AlbumResult<'a> {
+    /// (clipped) results of scanned files
+    tracks: Vec<(&'a String, ReplayGain)>,
+    album: Option<ReplayGain>,
+    /// files that could not be processed, with the reason
+    failed: Vec<(&'a String, String)>,
+    /// files whose tags differ from the scan (--check)
+    mismatched: Vec<&'a String>,
+    /// scanned files whose tags could not be written
+    unwritten: Vec<&'a String>,
+}
+
+impl<'a> AlbumResult<'a> {
+    /// Result of an album that is not processed at all, all `files` failed with `msg`
+    fn not_processed(
+        mut failed: Vec<(&'a String, String)>,
+        files: &[&'a String],
+        msg: String,
+    ) -> Self {
+        error!("{msg}; not processing album");
+        failed.extend(files.iter().map(|&path| (path, msg.clone())));
+        AlbumResult {
+            tracks: Vec::new(),
+            album: None,
+            failed,
+            mismatched: Vec::new(),
+            unwritten: Vec::new(),
+        }
+    }
+
+    /// Whether any track or the album clips (without prevention)
+    fn clips(&self) -> bool {
+        self.tracks
+            .iter()
+            .map(|(_, rg)| rg)
+            .chain(&self.album)
+            .any(|rg| rg.will_clip && !rg.clip_prevented)
+    }
+}
+
+/// Totals of all albums for the closing line of Human output
+#[derive(Default)]
+struct Summary {
+    files: usize,
+    failed: usize,
+    /// tracks with wrong tags (--check)
+    mismatched: usize,
+    /// tracks that clip without prevention
+    clipping: usize,
+    /// seconds
+    duration: f64,
+    album_gains: Vec<f64>,
+}
+
+impl Summary {
+    fn add(&mut self, result: &AlbumResult) {
+        self.files += result.tracks.len() + result.failed.len();
+        self.failed += result.failed.len() + result.unwritten.len();
+        self.mismatched += result.mismatched.len();
+        for (_, rg) in &result.tracks {
+            self.clipping += (rg.will_clip && !rg.clip_prevented) as usize;
+            self.duration += rg.duration;
+        }
+        self.album_gains
+            .extend(result.album.map(|album| album.gain));
+    }
+
+    fn display(&self, unit: &str, precision: usize) {
+        let mut parts = vec![format!("{} files", self.files)];
+        if self.failed > 0 {
+            parts.push(format!("{} failed", self.failed));
+        }
+        if self.mismatched > 0 {
+            parts.push(format!("{} with wrong tags", self.mismatched));
+        }
+        parts.push(format!("{} clipping", self.clipping));
+        match self.album_gains[..] {
+            [] => {}
+            [gain] => parts.push(format!("album gain {gain:.precision$} {unit}")),
+            ref gains => parts.push(format!("{} albums", gains.len())),
+        }
+        let seconds = self.duration.round() as u64;
+        parts.push(format!(
+            "{}:{:02}:{:02} total",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        ));
+        println!("Summary: {}", parts.join(", "));
+    }
+}
+
+/// Scan files, compute (album) gain, write tags and report
+fn process_album<'a>(
+    opts: &options::Opts,
+    files: &'a [String],
+    progress: &progress::Progress,
+    mut cache: Option<&mut cache::Cache>,
+) -> AlbumResult<'a> {
+    let scan_opts = ScanOptions {
+        reference: opts.reference,
+        pregain: opts.pre_gain,
+        detailed: opts.detailed,
+        true_peak: opts.true_peak,
+        preview: opts.preview_seconds,
+        window: opts.window,
+        downmix: opts.downmix,
+    };
+    // album gain needs EbuR128 of every track, so cached results only help for tracks
+    // sidecars are only allowed without album
+    let cached = |path: &str| {
+        let sidecar = opts.sidecar.then(|| sidecar::read(path, &scan_opts));
+        sidecar.flatten().or_else(|| match &cache {
+            // cached values are those of the whole file
+            Some(cache) if !opts.do_album && opts.window.is_none() => cache.get(path, &scan_opts),
+            _ => None,
+        })
+    };
+    // scan in parallel (order is preserved), files that fail to decode are skipped
+    let mut failed = Vec::new();
+    type State = ((u32, u32), EbuR128);
+    let (files, tracks): (Vec<&String>, Vec<(ReplayGain, Option<State>)>) = files
+        .par_iter()
+        .map(|path| {
+            let scan = match cached(path) {
+                Some(rg) => Ok((rg, None)),
+                // only album gain needs the state, and only its block histories
+                None => track_rg_streaming(path, &scan_opts).and_then(|(rg, mut e)| {
+                    // a continuous album is measured in a pass of its own
+                    if !opts.do_album || opts.continuous_album {
+                        return Ok((rg, None));
+                    }
+                    let format = (e.channels(), e.rate());
+                    compact_state(&mut e)?;
+                    Ok((rg, Some((format, e))))
+                }),
+            };
+            progress.tick(path);
+            (path, scan)
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|(path, scan)| match scan {
+            Ok(x) => Some((path, x)),
+            Err(e) => {
+                warn!("Skipping {path}: {e}");
+                failed.push((path, e.to_string()));
+                None
+            }
+        })
+        .unzip();
+    let (tracks, states): (Vec<ReplayGain>, Vec<Option<State>>) = tracks.into_iter().unzip();
+
+    let album: Option<ReplayGain> = if opts.continuous_album && !tracks.is_empty() {
+        match album_rg_continuous(&files, &tracks, &scan_opts) {
+            Ok(album) => {
+                let album = album.clipper("Album", opts.max_true_peak_level, opts.clip_mode);
+                Some(limit_gain(opts, "Album", album))
+            }
+            Err(e) => {
+                let msg = format!("Continuous album scan failed: {e}");
+                return AlbumResult::not_processed(failed, &files, msg);
+            }
+        }
+    } else if opts.do_album && !tracks.is_empty() {
+        // nothing came from the cache
+        let (formats, states): (Vec<(u32, u32)>, Vec<EbuR128>) =
+            states.into_iter().flatten().unzip();
+        let scans: Vec<(ReplayGain, EbuR128)> = tracks.iter().copied().zip(states).collect();
+        let formats = album_formats(formats);
+        if formats.len() > 1 {
+            let formats: Vec<String> = formats
+                .iter()
+                .map(|(channels, rate)| format!("{channels} ch @ {rate} Hz"))
+                .collect();
+            let msg = format!("Album tracks differ in format: {}", formats.join(", "));
+            if opts.strict {
+                return AlbumResult::not_processed(failed, &files, msg);
+            }
+            warn!("{msg}");
+        }
+        match album_rg(&scans, opts.reference, opts.pre_gain) {
+            Ok(album) => {
+                let album = album.clipper("Album", opts.max_true_peak_level, opts.clip_mode);
+                Some(limit_gain(opts, "Album", album))
+            }
+            Err(e) => {
+                let msg = format!("Album loudness measurement failed: {e}");
+                return AlbumResult::not_processed(failed, &files, msg);
+            }
+        }
     } else {
         None
     };
 
-    for (path, (rg, _)) in opts.files.iter().zip(tracks) {
+    // tags are written in a serial second pass: album gain needs every scan anyway, and
+    // this bounds I/O and never writes one file (listed twice) concurrently
+    let mut results = Vec::with_capacity(files.len());
+    let mut mismatched = Vec::new();
+    let mut unwritten = Vec::new();
+    for (path, scanned) in files.into_iter().zip(tracks) {
         // check clipping and maybe prevent it
-        let rg = rg.clipper(
-            opts.max_true_peak_level,
-            opts.warn_clip,
-            opts.clip_prevention,
-        );
+        let rg = scanned.clipper(path, opts.max_true_peak_level, opts.clip_mode);
+        let rg = limit_gain(opts, path, rg);
 
         // do requested stuff on file
         match opts.mode {
-            options::Mode::WriteExtended => tagger::write_tags(
-                path,
-                rg,
-                album,
-                true,
-                &opts.unit,
-                opts.lowercase,
-                opts.strip,
-                opts.id3v2version,
-            ),
-            options::Mode::Write => tagger::write_tags(
-                path,
-                rg,
-                album,
-                false,
-                &opts.unit,
-                opts.lowercase,
-                opts.strip,
-                opts.id3v2version,
-            ),
+            _ if opts.check.is_some() => {
+                let extended = opts.mode == options::Mode::WriteExtended;
+                if !check_tags(opts, path, rg, album, extended) {
+                    mismatched.push(path);
+                }
+            }
+            options::Mode::WriteExtended | options::Mode::Write => {
+                let extended = opts.mode == options::Mode::WriteExtended;
+                if !write_tags(opts, path, rg, album, extended) {
+                    unwritten.push(path);
+                }
+            }
             options::Mode::Noop => { /* no-op */ }
-            options::Mode::Delete => todo!(),
+            options::Mode::Delete => unreachable!("tags are deleted before scanning"),
+        }
+        // after writing, so the entry matches the tagged file; a changed Opus header
+        // changes the audio itself, and sidecar values were not measured by us
+        if let Some(cache) = cache.as_deref_mut() {
+            if !scanned.approximate
+                && !opts.sidecar
+                && (opts.opus_output_gain.is_none() || !tagger::is_opus(path))
+            {
+                cache.insert(path, &scanned, &scan_opts);
+            }
         }
 
         match opts.output {
-            options::OutputMode::Human => {
-                rg.display(&opts.unit)
-            },
-            options::OutputMode::Old => todo!("File\tMP3 gain\tdB gain\tMax Amplitude\tMax global_gain\tMin global_gain"),
-            options::OutputMode::New => todo!("File\tLoudness\tRange\tTrue_Peak\tTrue_Peak_dBTP\tReference\tWill_clip\tClip_prevent\tGain\tNew_Peak\tNew_Peak_dBTP"),
+            options::OutputMode::Human if opts.quiet => {}
+            options::OutputMode::Human if opts.normalize_to.is_some() => {
+                println!("{:.*}", opts.precision, rg.gain)
+            }
+            options::OutputMode::Human if opts.oneline => {
+                rg.display_oneline(path, &opts.output_unit, opts.precision)
+            }
+            options::OutputMode::Human if opts.detailed => {
+                rg.display_detailed(&opts.output_unit, opts.precision)
+            }
+            options::OutputMode::Human => rg.display(&opts.output_unit, opts.precision),
+            options::OutputMode::Old => rg.display_old(path),
+            options::OutputMode::New if opts.detailed => {
+                rg.display_new_detailed(path, &opts.output_unit, opts.precision, opts.both_units)
+            }
+            options::OutputMode::New => {
+                rg.display_new(path, &opts.output_unit, opts.precision, opts.both_units)
+            }
+            options::OutputMode::Json => {}
+        };
+        check_lra(opts, path, &rg);
+        results.push((path, rg));
+    }
+
+    if let Some(album) = album {
+        check_lra(opts, "Album", &album);
+        match opts.output {
+            options::OutputMode::Human
+                if opts.oneline && !opts.quiet && opts.normalize_to.is_none() =>
+            {
+                album.display_oneline("Album", &opts.output_unit, opts.precision)
+            }
+            options::OutputMode::Human => {}
+            options::OutputMode::Old => album.display_old("Album"),
+            options::OutputMode::New if opts.detailed => album.display_new_detailed(
+                "Album",
+                &opts.output_unit,
+                opts.precision,
+                opts.both_units,
+            ),
+            options::OutputMode::New => {
+                album.display_new("Album", &opts.output_unit, opts.precision, opts.both_units)
+            }
+            options::OutputMode::Json => {}
+        }
+    }
+
+    AlbumResult {
+        tracks: results,
+        album,
+        failed,
+        mismatched,
+        unwritten,
+    }
+}
+
+/// How tags are written, as set on the command line
+fn write_options(opts: &options::Opts, extended: bool) -> tagger::WriteOptions<'_> {
+    tagger::WriteOptions {
+        extended,
+        unit: &opts.unit,
+        precision: opts.precision,
+        lowercase: opts.lowercase,
+        keep_existing: opts.keep_tags,
+        strip: opts.strip,
+        id3v2version: opts.id3v2version,
+        rva2: opts.rva2,
+        tag_format: opts.tag_format,
+        reference_loudness: opts.reference_loudness_tag,
+    }
+}
+
+/// Report tags of `path` that differ from the scan (--check); true if there are none
+fn check_tags(
+    opts: &options::Opts,
+    path: &str,
+    rg: ReplayGain,
+    album: Option<ReplayGain>,
+    extended: bool,
+) -> bool {
+    if !tagger::is_taggable(path) {
+        error!("Can not check {path}: ASF/WMA and Matroska/WebM tags are not supported");
+        return false;
+    }
+    let tolerance = opts.check.unwrap_or_default();
+    let mismatches =
+        match tagger::tag_mismatches(path, rg, album, &write_options(opts, extended), tolerance) {
+            Ok(mismatches) => mismatches,
+            Err(e) => {
+                error!("Can not check {path}: {e}");
+                return false;
+            }
+        };
+    for mismatch in &mismatches {
+        let key = &mismatch.key;
+        let line = match (&mismatch.found, &mismatch.expected) {
+            (Some(found), Some(expected)) => {
+                format!("{path}: {key} is {found}, expected {expected}")
+            }
+            (None, Some(expected)) => format!("{path}: {key} is missing, expected {expected}"),
+            (Some(found), None) => format!("{path}: {key} is {found}, expected none"),
+            (None, None) => continue,
+        };
+        // keep list and JSON output parseable
+        if opts.output.is_human() && opts.normalize_to.is_none() {
+            println!("{line}");
+        } else {
+            eprintln!("{line}");
+        }
+    }
+    mismatches.is_empty()
+}
+
+/// Write RG tags to `path`, or just show them on a dry run; false if that failed
+fn write_tags(
+    opts: &options::Opts,
+    path: &str,
+    rg: ReplayGain,
+    album: Option<ReplayGain>,
+    extended: bool,
+) -> bool {
+    if is_stdin(path) {
+        return true;
+    }
+    if !may_write(opts, path) {
+        return false;
+    }
+    // gain added to the Opus header, which is written along with the tags
+    let (header_gain, rg, album) = match opts.opus_output_gain {
+        Some(which) if tagger::is_opus(path) => {
+            let applied = match (which, album) {
+                (options::OpusGain::Album, Some(album)) => album.gain,
+                _ => rg.gain,
+            };
+            if opts.dry_run {
+                eprintln!("Would add {applied:.2} dB to Opus output gain of {path}");
+            }
+            (
+                Some(applied),
+                rg.with_applied_gain(applied),
+                album.map(|album| album.with_applied_gain(applied)),
+            )
+        }
+        _ => (None, rg, album),
+    };
+    let write_opts = write_options(opts, extended);
+    if opts.skip_tagged {
+        match tagger::is_tagged(path, rg, album, &write_opts) {
+            Ok(true) => {
+                debug!("{path} is already tagged, skipping");
+                return true;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                error!("Failed to read tags of {path}: {e}");
+                return false;
+            }
+        }
+    }
+    let result = if opts.dry_run {
+        tagger::preview_tags(path, rg, album, &write_opts).map(|tags| {
+            for (key, value) in tags {
+                eprintln!("Would write {key}={value} to {path}");
+            }
+        })
+    } else {
+        // both on the same (temporary) file with --atomic
+        let write = |path: &Path| {
+            if let Some(applied) = header_gain {
+                // scanned audio already includes the current header gain
+                loudgainer::opus::read_output_gain(path)
+                    .and_then(|old| loudgainer::opus::write_output_gain(path, old + applied))
+                    .map_err(|e| {
+                        io::Error::new(e.kind(), format!("can not set Opus output gain: {e}"))
+                    })?;
+            }
+            tagger::write_tags(path, rg, album, &write_opts)
         };
+        if opts.atomic {
+            tagger::atomically(path, write)
+        } else {
+            write(Path::new(path))
+        }
+    };
+    if let Err(e) = &result {
+        error!("Failed to write tags of {path}: {e}");
+    }
+    result.is_ok()
+}
+
+/// Header of the extra New columns with --units both
+fn other_unit_header(opts: &options::Opts) -> String {
+    if !opts.both_units {
+        return String::new();
+    }
+    let other = other_unit(&opts.output_unit);
+    format!("\tRange_{other}\tGain_{other}")
+}
+
+/// Unit fields and, with --units both, gain/range fields in the other unit of JSON `rg`
+fn add_units(opts: &options::Opts, rg: &mut serde_json::Value) {
+    rg["unit"] = opts.output_unit.as_str().into();
+    if opts.both_units {
+        let other = other_unit(&opts.output_unit).to_lowercase();
+        rg[format!("range_{other}")] = rg["range"].clone();
+        rg[format!("gain_{other}")] = rg["gain"].clone();
+    }
+}
+
+/// Clamp gain to 0 dB with --no-positive-gain (after clip handling)
+fn limit_gain(opts: &options::Opts, name: &str, rg: ReplayGain) -> ReplayGain {
+    if opts.no_positive_gain && rg.gain > 0.0 {
+        info!("{name}: gain {:.2} dB clamped to 0 dB", rg.gain);
+        return ReplayGain { gain: 0.0, ..rg };
+    }
+    rg
+}
+
+/// Point out very dynamic material (loudness range above --warn-lra)
+fn check_lra(opts: &options::Opts, name: &str, rg: &ReplayGain) {
+    match opts.warn_lra {
+        Some(max) if rg.loudness_range > max => {
+            let msg = format!(
+                "{name}: loudness range {:.2} LU exceeds {max:.2} LU",
+                rg.loudness_range
+            );
+            if opts.output == options::OutputMode::Human {
+                if !opts.quiet {
+                    println!("Warning: {msg}");
+                }
+            } else {
+                warn!("{msg}");
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Standard input can not be tagged; that is skipped with a warning, not a failure
+fn is_stdin(path: &str) -> bool {
+    let stdin = path == loudgainer::audio::STDIN;
+    if stdin {
+        warn!("Not tagging standard input");
+    }
+    stdin
+}
+
+/// Refuse formats we can not tag, and those with experimental support unless explicitly
+/// allowed
+fn may_write(opts: &options::Opts, path: &str) -> bool {
+    if !tagger::is_taggable(path) {
+        error!("Not tagging {path}: ASF/WMA and Matroska/WebM tags are not supported");
+        return false;
+    }
+    if tagger::is_experimental(path) && !opts.allow_experimental {
+        error!("Not tagging {path}: WAV/AIFF tagging is experimental, use --allow-experimental");
+        return false;
     }
+    true
 }
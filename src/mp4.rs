@@ -0,0 +1,215 @@
+//! Freeform (`----`) atoms of the MP4 metadata item list (moov.udta.meta.ilst)
+//!
+//! lofty only turns freeform atoms with UTF-8 data into tag items, but some taggers store
+//! `----:com.apple.iTunes:REPLAYGAIN_*` with the implicit (0) data type. These are read here,
+//! so stored RG values can still be compared.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Size and type of box header
+const HEADER_LEN: usize = 8;
+/// Data atom: type indicator (4) and locale (4) before the value
+const DATA_PREFIX_LEN: usize = 8;
+/// Well-known data types of value
+const TYPE_IMPLICIT: u32 = 0;
+const TYPE_UTF8: u32 = 1;
+
+/// `(name, value)` of every textual freeform atom with `mean`, in file order
+///
+/// Values of implicit type are taken if they are valid UTF-8. A file without metadata
+/// yields an empty list.
+pub fn read_freeform<P: AsRef<Path>>(path: P, mean: &str) -> io::Result<Vec<(String, String)>> {
+    let mut file = File::open(path)?;
+    let moov = match read_moov(&mut file)? {
+        Some(moov) => moov,
+        None => return Ok(Vec::new()),
+    };
+    let ilst = find_box(&moov, b"udta")
+        .and_then(|udta| find_box(udta, b"meta"))
+        // meta is a full box, version and flags come first
+        .and_then(|meta| meta.get(4..))
+        .and_then(|meta| find_box(meta, b"ilst"));
+    Ok(ilst
+        .map(|ilst| {
+            boxes(ilst)
+                .filter(|&(kind, _)| kind == b"----")
+                .filter_map(|(_, item)| freeform(item, mean))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Name and value of freeform item, if it has `mean` and a textual value
+fn freeform(item: &[u8], mean: &str) -> Option<(String, String)> {
+    let field = |kind: &[u8; 4]| find_box(item, kind).and_then(|b| b.get(4..));
+    if field(b"mean")? != mean.as_bytes() {
+        return None;
+    }
+    let name = String::from_utf8(field(b"name")?.to_vec()).ok()?;
+    let data = find_box(item, b"data")?;
+    // the top byte of the type indicator is reserved
+    let data_type = be_u32(data.get(..4)?) & 0x00ff_ffff;
+    if data_type != TYPE_IMPLICIT && data_type != TYPE_UTF8 {
+        return None;
+    }
+    let value = String::from_utf8(data.get(DATA_PREFIX_LEN..)?.to_vec()).ok()?;
+    Some((name, value))
+}
+
+/// Contents of the top-level moov box, which may come after the media data
+fn read_moov(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos + HEADER_LEN as u64 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let (mut size, mut header_len) = (be_u32(&header[..4]) as u64, HEADER_LEN as u64);
+        if size == 1 {
+            let mut large = [0; 8];
+            file.read_exact(&mut large)?;
+            size = u64::from_be_bytes(large);
+            header_len += 8;
+        } else if size == 0 {
+            size = len - pos;
+        }
+        if size < header_len || pos + size > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad MP4 box size",
+            ));
+        }
+        if &header[4..] == b"moov" {
+            let mut moov = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+/// Contents of first child box of `kind`
+fn find_box<'a>(parent: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(parent).find(|&(k, _)| k == kind).map(|(_, b)| b)
+}
+
+/// `(type, contents)` of child boxes, stopping at the first malformed one
+fn boxes(mut parent: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        if parent.len() < HEADER_LEN {
+            return None;
+        }
+        let size = match be_u32(&parent[..4]) as usize {
+            0 => parent.len(),
+            size => size,
+        };
+        if size < HEADER_LEN || size > parent.len() {
+            return None;
+        }
+        let (current, rest) = parent.split_at(size);
+        parent = rest;
+        Some((&current[4..HEADER_LEN], &current[HEADER_LEN..]))
+    })
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut atom = ((HEADER_LEN + contents.len()) as u32)
+            .to_be_bytes()
+            .to_vec();
+        atom.extend_from_slice(kind);
+        atom.extend_from_slice(contents);
+        atom
+    }
+
+    /// `----` item with `mean`, `name` and `value` of `data_type`
+    fn freeform_item(mean: &str, name: &str, data_type: u32, value: &str) -> Vec<u8> {
+        let full = |s: &str| [&[0; 4], s.as_bytes()].concat();
+        let mut data = data_type.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(value.as_bytes());
+        [
+            atom(b"mean", &full(mean)),
+            atom(b"name", &full(name)),
+            atom(b"data", &data),
+        ]
+        .concat()
+    }
+
+    const MEAN: &str = "com.apple.iTunes";
+
+    #[test]
+    fn freeform_keeps_value_with_and_without_db_suffix() {
+        for value in ["-6.50 dB", "-6.50"] {
+            for data_type in [TYPE_IMPLICIT, TYPE_UTF8] {
+                let item = freeform_item(MEAN, "REPLAYGAIN_TRACK_GAIN", data_type, value);
+                assert_eq!(
+                    freeform(&item, MEAN),
+                    Some(("REPLAYGAIN_TRACK_GAIN".to_owned(), value.to_owned()))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn freeform_skips_other_mean_and_binary_data() {
+        let item = freeform_item(
+            "org.example",
+            "REPLAYGAIN_TRACK_GAIN",
+            TYPE_UTF8,
+            "-6.50 dB",
+        );
+        assert_eq!(freeform(&item, MEAN), None);
+        // 21 is a big-endian signed integer
+        let item = freeform_item(MEAN, "REPLAYGAIN_TRACK_GAIN", 21, "-6.50 dB");
+        assert_eq!(freeform(&item, MEAN), None);
+    }
+
+    #[test]
+    fn read_freeform_finds_ilst_after_media_data() {
+        let ilst = atom(
+            b"ilst",
+            &[
+                atom(
+                    b"----",
+                    &freeform_item(MEAN, "REPLAYGAIN_TRACK_GAIN", 1, "-6.50 dB"),
+                ),
+                atom(b"\xa9nam", b""),
+                atom(
+                    b"----",
+                    &freeform_item(MEAN, "REPLAYGAIN_TRACK_PEAK", 0, "0.988"),
+                ),
+            ]
+            .concat(),
+        );
+        let meta = atom(b"meta", &[&[0; 4], &ilst[..]].concat());
+        let moov = atom(b"moov", &atom(b"udta", &meta));
+        let file = [
+            atom(b"ftyp", b"M4A \0\0\0\0"),
+            atom(b"mdat", &[0; 16]),
+            moov,
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("loudgainer-{}-rg.m4a", std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        let atoms = read_freeform(&path, MEAN);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            atoms.unwrap(),
+            [
+                ("REPLAYGAIN_TRACK_GAIN".to_owned(), "-6.50 dB".to_owned()),
+                ("REPLAYGAIN_TRACK_PEAK".to_owned(), "0.988".to_owned()),
+            ]
+        );
+    }
+}
@@ -1,9 +1,12 @@
 use std::process::exit;
-use std::string::ParseError;
 
 use gumdrop::Options;
+use loudgainer::replay_gain::ClipMode;
+use loudgainer::tagger::{Id3v2version, TagFormat};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Upper bound of --threads, far above any sensible value
+const MAX_THREADS: usize = 1024;
 
 /// loudgainer is a loudness normalizer that scans music files and calculates loudness-normalized gain and loudness peak values according to the EBU R128 standard, and can optionally write ReplayGain-compatible metadata.
 ///
@@ -12,9 +15,15 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 /// loudgainer will not modify the actual audio data, but instead just write ReplayGain tags if so requested. It is up to the player to interpret these. (In some players, you need to enable this feature.)
 ///
 /// loudgainer currently supports writing tags to the following file types:
-/// FLAC (.flac), Ogg (.ogg, .oga, .spx, .opus), MP2 (.mp2), MP3 (.mp3), MP4 (.mp4, .m4a), ASF/WMA (.asf, .wma), WavPack (.wv), APE (.ape).
+/// FLAC (.flac), Ogg (.ogg, .oga, .spx, .opus), MP2 (.mp2), MP3 (.mp3), MP4 (.mp4, .m4a, .m4b), WavPack (.wv), APE (.ape).
 ///
-/// Experimental, use with care: WAV (.wav), AIFF (.aiff, .aif, .snd).
+/// Scanned only: ASF/WMA (.asf, .wma), Matroska/WebM (.mka, .webm).
+///
+/// Experimental, use with care (needs --allow-experimental): WAV (.wav), AIFF (.aiff, .aif, .snd).
+///
+/// Exit status: 0 on success, 1 if some files could not be processed, 2 for invalid arguments,
+/// 3 if some track or album will clip (and clipping was not prevented), 4 if --check found
+/// missing or wrong tags.
 #[derive(Debug, Options)]
 struct MyOptions {
     // Contains fi
@@ -38,7 +47,25 @@ struct MyOptions {
     #[options(help = "Calculate album gain (and track gain)")]
     album: bool,
 
-    #[options(help = "Ignore clipping warnings")]
+    #[options(
+        no_short,
+        help = "Calculate album gain with every directory as its own album (implies --album)"
+    )]
+    per_directory_album: bool,
+
+    #[options(
+        no_short,
+        help = "Calculate one album gain for all files, whatever directory they are in (implies --album, also with --recursive)"
+    )]
+    single_album: bool,
+
+    #[options(
+        no_short,
+        help = "Measure album loudness as one gapless stream of all tracks in order, for live albums and DJ mixes (implies --album, decodes every track twice)"
+    )]
+    continuous_album: bool,
+
+    #[options(help = "Ignore clipping warnings (-k/-K still prevent clipping)")]
     clip: bool,
 
     #[options(
@@ -56,17 +83,31 @@ struct MyOptions {
 
     #[options(
         short = "d",
-        help = "Apply n dB/LU pre-gain value (-5 for -23 LUFS target)",
+        help = "Apply n dB/LU pre-gain value, fractions allowed (-5 for -23 LUFS target)",
         meta = "n"
     )]
     pregain: Option<f64>,
 
+    #[options(
+        no_short,
+        help = "Loudness target in LUFS (default -18 as per ReplayGain 2.0, -23 for EBU R128)",
+        meta = "LUFS"
+    )]
+    reference: Option<f64>,
+
+    #[options(
+        no_short,
+        help = "Only print the gain in dB to reach LUFS (e.g. -16 for podcasts), one line per file; tags get the same gain. Excludes -d and --reference",
+        meta = "LUFS"
+    )]
+    normalize_to: Option<f64>,
+
     #[options(
         short = "s",
         help = "
         TAGMODES:
             d: Delete ReplayGain tags from files.
-            i: Write ReplayGain 2.0 tags to files. ID3v2 for MP2, MP3, WAV and AIFF; Vorbis Comments for FLAC, Ogg, Speex and Opus; iTunes-type metadata for MP4/M4A; WMA tags for ASF/WMA; APEv2 tags for WavPack and APE.
+            i: Write ReplayGain 2.0 tags to files. ID3v2 for MP2, MP3, WAV and AIFF; Vorbis Comments for FLAC, Ogg, Speex and Opus; iTunes-type metadata for MP4/M4A; APEv2 tags for WavPack and APE. ASF/WMA and Matroska/WebM are scanned only.
             e: like '-s i', plus extra tags (reference, ranges).
             l: like '-s e', but LU units instead of dB.
             s: Don't write ReplayGain tags (default).
@@ -74,12 +115,65 @@ struct MyOptions {
     )]
     tagmode: Tagmode,
 
+    #[options(
+        short = "n",
+        help = "Show which tags would be written or deleted, without modifying any file"
+    )]
+    dry_run: bool,
+
+    #[options(
+        no_short,
+        help = "Don't rewrite files whose ReplayGain tags already match the scan"
+    )]
+    skip_tagged: bool,
+
+    #[options(
+        no_short,
+        help = "Only compare stored tags with the scan (tags of -s i, or -s e/l) and report differences; exit status 4 if any file is off. No file is written"
+    )]
+    check: bool,
+
+    #[options(
+        no_short,
+        help = "Largest difference of gains, peaks and ranges that --check accepts (default 0.01)",
+        meta = "n"
+    )]
+    tolerance: Option<f64>,
+
+    #[options(
+        no_short,
+        help = "Opus: apply track or album gain in the OpusHead output gain field. This modifies the bitstream; tags are written relative to it",
+        meta = "track|album"
+    )]
+    opus_output_gain: Option<OpusGain>,
+
+    #[options(
+        no_short,
+        help = "Refuse albums whose tracks differ in channel count or sample rate"
+    )]
+    strict: bool,
+
+    #[options(
+        no_short,
+        help = "Write tags to a copy of the file and rename it over the original, so a crash can not corrupt it"
+    )]
+    atomic: bool,
+
+    #[options(no_short, help = "Allow writing experimental WAV/AIFF tags")]
+    allow_experimental: bool,
+
     #[options(
         short = "L",
-        help = "Force lowercase 'REPLAYGAIN_*' tags (MP2/MP3/MP4/ASF/WMA/WAV/AIFF only). This is non-standard, but sometimes needed"
+        help = "Force lowercase 'REPLAYGAIN_*' tags (MP2/MP3/MP4/WAV/AIFF only). This is non-standard, but sometimes needed"
     )]
     lowercase: bool,
 
+    #[options(
+        no_short,
+        help = "Only add missing ReplayGain tags, keep the values of existing ones (e.g. set by hand)"
+    )]
+    keep_loudness_tags: bool,
+
     #[options(
         short = "S",
         help = "Strip tag types other than ID3v2 from MP2/MP3 files (i.e. ID3v1, APEv2). Strip tag types other than APEv2 from WavPack/APE files (i.e. ID3v1)"
@@ -93,6 +187,26 @@ struct MyOptions {
     )]
     id3v2version: Id3v2version,
 
+    #[options(
+        no_short,
+        help = "Also write track gain and peak as ID3v2.4 RVA2 frame to MP2/MP3/WAV/AIFF files, replacing other RVA2 frames (some players prefer it); not with -I 3"
+    )]
+    rva2: bool,
+
+    #[options(
+        no_short,
+        help = "Tag type to write where the format has several (e.g. apev2 for MP3); unsupported types fall back to the default",
+        meta = "id3v2|apev2|vorbis"
+    )]
+    tag_type: Option<TagFormat>,
+
+    #[options(
+        no_short,
+        help = "Write n LUFS as REPLAYGAIN_REFERENCE_LOUDNESS (-s e/l) instead of the loudness target",
+        meta = "n"
+    )]
+    reference_loudness_tag: Option<f64>,
+
     #[options(help = "Database-friendly tab-delimited list output (mp3gain-compatible)")]
     output: bool,
 
@@ -103,15 +217,150 @@ struct MyOptions {
     )]
     output_new: bool,
 
-    #[options(help = "Database-friendly tab-delimited list output (mp3gain-compatible)")]
+    #[options(
+        short = "J",
+        long = "output-json",
+        help = "JSON output of all tracks and albums, for analysis pipelines"
+    )]
+    output_json: bool,
+
+    #[options(
+        short = "1",
+        help = "Human output with one line per file: gain, peak and loudness"
+    )]
+    oneline: bool,
+
+    #[options(
+        no_short,
+        help = "Decimal places of gains, ranges and loudness in output and tags (default 2)",
+        meta = "N"
+    )]
+    precision: Option<usize>,
+
+    #[options(
+        no_short,
+        help = "Units of gains and ranges in output (not tags): dB, LU, or both for extra New/JSON columns in the other unit (default: dB, LU with -s l)",
+        meta = "dB|LU|both"
+    )]
+    units: Option<Units>,
+
+    #[options(
+        no_short,
+        help = "After scanning, print how many tracks got which gain, in 1 dB buckets (on stderr with -o/-O, in the document with -J)"
+    )]
+    histogram: bool,
+
+    #[options(
+        no_short,
+        help = "Also measure max momentary and short-term loudness (Human, new-format and JSON output)"
+    )]
+    detailed: bool,
+
+    #[options(help = "Don't print scanning status messages")]
     quiet: bool,
+
+    #[options(
+        short = "R",
+        help = "Scan directories recursively; with --album every directory is an album (unless --single-album)"
+    )]
+    recursive: bool,
+
+    #[options(
+        no_short,
+        help = "Peak measurement: accurate (true peak, default) or fast (sample peak only)",
+        meta = "fast|accurate"
+    )]
+    tp_mode: Option<TpMode>,
+
+    #[options(no_short, help = "Measure sample peak only, same as --tp-mode fast")]
+    sample_peak_only: bool,
+
+    #[options(
+        no_short,
+        help = "Measure surround files (3 to 8 channels) as a stereo downmix, peaks included"
+    )]
+    downmix: bool,
+
+    #[options(
+        no_short,
+        help = "Quick approximate scan of only the first n seconds of every file",
+        meta = "n"
+    )]
+    preview_seconds: Option<f64>,
+
+    #[options(
+        no_short,
+        help = "Only measure from second START to second END of every file (e.g. 0:5 for an intro); not with --preview-seconds or --sidecar",
+        meta = "START:END"
+    )]
+    window: Option<Window>,
+
+    #[options(
+        no_short,
+        help = "Never apply positive gain (for players that misbehave with it)"
+    )]
+    no_positive_gain: bool,
+
+    #[options(
+        no_short,
+        help = "Warn if loudness range is above n LU (very dynamic material)",
+        meta = "n"
+    )]
+    warn_lra: Option<f64>,
+
+    #[options(
+        no_short,
+        help = "Reuse scan results of unchanged files, stored in FILE (JSON)",
+        meta = "FILE"
+    )]
+    cache: Option<String>,
+
+    #[options(
+        no_short,
+        help = "Take loudness and peak of FILE from FILE.lufs or FILE.json (JSON, as in -J output) instead of scanning it; not with --album"
+    )]
+    sidecar: bool,
+
+    #[options(
+        no_short,
+        help = "Also scan the files listed in M3U/M3U8 playlist FILE (repeatable); with --per-directory-album every directory is an album",
+        meta = "FILE"
+    )]
+    playlist: Vec<String>,
+
+    #[options(
+        no_short,
+        help = "Only find files with these extensions in directories (comma-separated, e.g. flac,mp3,opus; default all supported); files given directly are always tried",
+        meta = "LIST"
+    )]
+    extensions: Option<String>,
+
+    #[options(
+        no_short,
+        help = "Scan at most N files in parallel (0 = one per CPU, the default; at most 1024); tags are written one file at a time, after the whole album is scanned",
+        meta = "N"
+    )]
+    threads: Option<usize>,
+}
+
+impl MyOptions {
+    /// -k and -K prevent clipping even with -c, which only silences the warnings
+    fn clip_mode(&self) -> ClipMode {
+        if self.noclip || self.maxtpl.is_some() {
+            ClipMode::Prevent
+        } else if self.clip {
+            ClipMode::Ignore
+        } else {
+            ClipMode::Warn
+        }
+    }
 }
 
 #[derive(Debug, Default, PartialEq)]
 enum Tagmode {
     /// Delete ReplayGain tags from files.
     D,
-    /// Write ReplayGain 2.0 tags to files. ID3v2 for MP2, MP3, WAV and AIFF; Vorbis Comments for FLAC, Ogg, Speex and Opus; iTunes-type metadata for MP4/M4A; WMA tags for ASF/WMA; APEv2 tags for WavPack and APE.
+    /// Write ReplayGain 2.0 tags to files. ID3v2 for MP2, MP3, WAV and AIFF; Vorbis Comments for FLAC, Ogg, Speex and Opus; iTunes-type metadata for MP4/M4A; APEv2 tags for WavPack and APE. ASF/WMA and Matroska/WebM are scanned only.
     I,
     /// like '-s i', plus extra tags (reference, ranges).
     E,
@@ -122,36 +371,21 @@ enum Tagmode {
     S,
 }
 
-impl std::str::FromStr for Tagmode {
-    type Err = ParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match &s.to_ascii_lowercase().chars().next().unwrap() {
-            'd' => Ok(Self::D),
-            'i' => Ok(Self::I),
-            'e' => Ok(Self::E),
-            'l' => Ok(Self::L),
-            's' => Ok(Self::S),
-            _ => panic!("Invalid tag mode!"),
-        }
-    }
-}
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid tag mode '{0}'; expected one of d, i, e, l, s")]
+struct ParseTagmodeError(String);
 
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
-pub enum Id3v2version {
-    V3,
-    #[default]
-    V4,
-}
-
-impl std::str::FromStr for Id3v2version {
-    type Err = ParseError;
+impl std::str::FromStr for Tagmode {
+    type Err = ParseTagmodeError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match &s.to_ascii_lowercase().chars().next().unwrap() {
-            '3' => Ok(Self::V3),
-            '4' => Ok(Self::V4),
-            _ => panic!("Invalid ID3v2 version; only 3 and 4 are supported."),
+        match s.to_ascii_lowercase().chars().next() {
+            Some('d') => Ok(Self::D),
+            Some('i') => Ok(Self::I),
+            Some('e') => Ok(Self::E),
+            Some('l') => Ok(Self::L),
+            Some('s') => Ok(Self::S),
+            _ => Err(ParseTagmodeError(s.to_owned())),
         }
     }
 }
@@ -164,6 +398,8 @@ pub enum OutputMode {
     Old,
     /// output new style list: File;Loudness;Range;Gain;Reference;Peak;Peak dBTP;Clipping;Clip-prevent
     New,
+    /// output one JSON document with all tracks and albums
+    Json,
 }
 
 impl OutputMode {
@@ -176,7 +412,7 @@ impl OutputMode {
 pub enum Mode {
     /// like Write mode, with extra tags (reference, ranges).
     WriteExtended,
-    /// Write ReplayGain 2.0 tags to files. ID3v2 for MP2, MP3, WAV and AIFF; Vorbis Comments for FLAC, Ogg, Speex and Opus; iTunes-type metadata for MP4/M4A; WMA tags for ASF/WMA; APEv2 tags for WavPack and APE.
+    /// Write ReplayGain 2.0 tags to files. ID3v2 for MP2, MP3, WAV and AIFF; Vorbis Comments for FLAC, Ogg, Speex and Opus; iTunes-type metadata for MP4/M4A; APEv2 tags for WavPack and APE. ASF/WMA and Matroska/WebM are scanned only.
     Write,
     #[default]
     /// Don't write ReplayGain tags.
@@ -185,34 +421,187 @@ pub enum Mode {
     Delete,
 }
 
+/// How peaks are measured
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum TpMode {
+    Fast,
+    Accurate,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid true peak mode '{0}'; expected fast or accurate")]
+struct ParseTpModeError(String);
+
+impl std::str::FromStr for TpMode {
+    type Err = ParseTpModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fast" => Ok(Self::Fast),
+            "accurate" => Ok(Self::Accurate),
+            _ => Err(ParseTpModeError(s.to_owned())),
+        }
+    }
+}
+
+/// Part of the audio that is measured, in seconds
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Window(f64, f64);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid window '{0}'; expected START:END in seconds, with START < END")]
+struct ParseWindowError(String);
+
+impl std::str::FromStr for Window {
+    type Err = ParseWindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds = |n: &str| n.trim().parse::<f64>().ok().filter(|n| n.is_finite());
+        match s
+            .split_once(':')
+            .map(|(start, end)| (seconds(start), seconds(end)))
+        {
+            Some((Some(start), Some(end))) if 0.0 <= start && start < end => Ok(Self(start, end)),
+            _ => Err(ParseWindowError(s.to_owned())),
+        }
+    }
+}
+
+/// Units of gains and ranges in output
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Units {
+    Db,
+    Lu,
+    /// unit of the tag mode, plus the other one in New and JSON output
+    Both,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid units '{0}'; expected dB, LU or both")]
+struct ParseUnitsError(String);
+
+impl std::str::FromStr for Units {
+    type Err = ParseUnitsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "db" => Ok(Self::Db),
+            "lu" => Ok(Self::Lu),
+            "both" => Ok(Self::Both),
+            _ => Err(ParseUnitsError(s.to_owned())),
+        }
+    }
+}
+
+/// Which gain goes into the Opus header
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OpusGain {
+    Track,
+    Album,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid Opus output gain '{0}'; expected track or album")]
+pub struct ParseOpusGainError(String);
+
+impl std::str::FromStr for OpusGain {
+    type Err = ParseOpusGainError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "track" => Ok(Self::Track),
+            "album" => Ok(Self::Album),
+            _ => Err(ParseOpusGainError(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Opts {
     /// files to be processed
     pub files: Vec<String>,
     /// output mode
     pub output: OutputMode,
+    /// Human output: one line per file
+    pub oneline: bool,
     /// unit: dB or LU
     pub unit: String,
+    /// output unit of gains and ranges when tags are written in `unit`
+    pub output_unit: String,
+    /// also output gains and ranges in the other unit (New/JSON)
+    pub both_units: bool,
+    /// decimal places of gains, ranges and loudness
+    pub precision: usize,
+    /// report distribution of track gains
+    pub histogram: bool,
     /// Working Mode (cmd)
     pub mode: Mode,
-    /// pre-gain
+    /// only report what the mode would change
+    pub dry_run: bool,
+    /// leave files with up-to-date tags alone
+    pub skip_tagged: bool,
+    /// compare tags instead of writing them, with this tolerance
+    pub check: Option<f64>,
+    /// Opus: gain to apply in the header
+    pub opus_output_gain: Option<OpusGain>,
+    /// permit tagging WAV/AIFF
+    pub allow_experimental: bool,
+    /// loudness target in LUFS
+    pub reference: f64,
+    /// pre-gain in dB (1 dB == 1 LU); also shifts `loudness_reference` to reference + pre_gain LUFS
     pub pre_gain: f64,
+    /// LUFS; plain `target - loudness` output instead of RG details (sets `reference`)
+    pub normalize_to: Option<f64>,
     /// dBTP; default for -k, as per EBU Tech 3343
     pub max_true_peak_level: f64,
-    /// prevent clipping
-    pub clip_prevention: bool,
-    /// warn if clipping happens
-    pub warn_clip: bool,
+    /// ignore (-c), warn about (default) or prevent (-k/-K) clipping
+    pub clip_mode: ClipMode,
     /// calculate album gain
     pub do_album: bool,
-    /// force MP3 ID3v2 tags to lowercase?
+    /// measure true peak, else sample peak only
+    pub true_peak: bool,
+    /// seconds; only scan the start of files
+    pub preview_seconds: Option<f64>,
+    /// only measure from .0 to .1 seconds
+    pub window: Option<(f64, f64)>,
+    /// measure surround as stereo downmix
+    pub downmix: bool,
+    /// clamp gain to <= 0 dB
+    pub no_positive_gain: bool,
+    /// LU; warn about loudness ranges above this
+    pub warn_lra: Option<f64>,
+    /// group album by parent directory (explicitly, or by --recursive --album)
+    pub per_directory_album: bool,
+    /// album loudness of all tracks fed into one meter, in order
+    pub continuous_album: bool,
+    /// album tracks must share format
+    pub strict: bool,
+    /// force RG tags to lowercase (where the tag format allows it)?
     pub lowercase: bool,
+    /// only write tags that are not stored yet
+    pub keep_tags: bool,
     /// MP3 ID3v2: strip other tag types?
     pub strip: bool,
+    /// write tags to a copy, then rename it over the file
+    pub atomic: bool,
     /// MP3 ID3v2 version to write; can be 3 or 4
     pub id3v2version: Id3v2version,
-    /// silent
+    /// also write an RVA2 frame (ID3v2.4)
+    pub rva2: bool,
+    /// tag type to write instead of the format's default
+    pub tag_format: Option<TagFormat>,
+    /// LUFS; REPLAYGAIN_REFERENCE_LOUDNESS value instead of reference + pre-gain
+    pub reference_loudness_tag: Option<f64>,
+    /// measure momentary/short-term loudness maxima
+    pub detailed: bool,
+    /// silent: no human output, only errors are logged
     pub quiet: bool,
+    /// scan result cache file
+    pub cache: Option<String>,
+    /// read measured values from sidecar files where present
+    pub sidecar: bool,
+    /// max number of parallel scans (one per CPU if None)
+    pub threads: Option<usize>,
 }
 
 pub fn parse_arguments() -> Opts {
@@ -220,36 +609,163 @@ pub fn parse_arguments() -> Opts {
     // process version
     if opts.version {
         println!("loudgainer v{VERSION}");
-        println!("ebur128 v0.1.6 based on libebur128 1.2.6");
+        println!(
+            "ebur128 v{} (Rust port of libebur128)",
+            env!("EBUR128_VERSION")
+        );
         exit(0)
     };
 
-    let mut no_clip = opts.noclip;
+    let output_flags: Vec<&str> = [
+        (opts.output, "-o/--output"),
+        (opts.output_new, "-O/--output-new"),
+        (opts.output_json, "-J/--output-json"),
+        (opts.oneline, "-1/--oneline"),
+    ]
+    .into_iter()
+    .filter_map(|(set, flag)| set.then_some(flag))
+    .collect();
+    if output_flags.len() > 1 {
+        eprintln!(
+            "loudgainer: conflicting output modes: {}",
+            output_flags.join(", ")
+        );
+        exit(2)
+    }
+
+    if opts.sample_peak_only && opts.tp_mode == Some(TpMode::Accurate) {
+        eprintln!("loudgainer: conflicting peak modes: --sample-peak-only, --tp-mode accurate");
+        exit(2)
+    }
+
+    if opts.check && opts.tagmode == Tagmode::D {
+        eprintln!("loudgainer: --check can not be combined with -s d");
+        exit(2)
+    }
+    if let Some(tolerance) = opts.tolerance {
+        if !tolerance.is_finite() || tolerance < 0.0 {
+            eprintln!("loudgainer: invalid tolerance (must be >= 0)");
+            exit(2)
+        }
+    }
+
+    if opts.rva2 && opts.id3v2version == Id3v2version::V3 {
+        eprintln!("loudgainer: --rva2 needs ID3v2.4, it can not be combined with -I 3");
+        exit(2)
+    }
+    if opts.single_album && opts.per_directory_album {
+        eprintln!("loudgainer: --single-album can not be combined with --per-directory-album");
+        exit(2)
+    }
+    if opts.continuous_album && (opts.preview_seconds.is_some() || opts.window.is_some()) {
+        eprintln!(
+            "loudgainer: --continuous-album can not be combined with --preview-seconds or --window"
+        );
+        exit(2)
+    }
+    if opts.sidecar
+        && (opts.album || opts.per_directory_album || opts.single_album || opts.continuous_album)
+    {
+        eprintln!("loudgainer: --sidecar can not be combined with --album");
+        exit(2)
+    }
 
+    let clip_mode = opts.clip_mode();
     let pre_gain = opts.pregain.unwrap_or(0.0);
     if !pre_gain.is_finite() {
-        panic!("Invalid pregain value (dB/LU)");
+        eprintln!("loudgainer: invalid pre-gain (dB/LU)");
+        exit(2)
+    }
+    let reference = opts.reference.unwrap_or(-18.0);
+    if !reference.is_finite() {
+        eprintln!("loudgainer: invalid reference loudness (LUFS)");
+        exit(2)
+    }
+    // the target is a plain loudness, RG reference and pre-gain do not apply
+    let (reference, pre_gain) = match opts.normalize_to {
+        Some(_) if opts.reference.is_some() || opts.pregain.is_some() => {
+            eprintln!("loudgainer: --normalize-to can not be combined with -d or --reference");
+            exit(2)
+        }
+        Some(target) if !target.is_finite() => {
+            eprintln!("loudgainer: invalid normalization target (LUFS)");
+            exit(2)
+        }
+        Some(target) => (target, 0.0),
+        None => (reference, pre_gain),
+    };
+    if opts.window.is_some() && (opts.preview_seconds.is_some() || opts.sidecar) {
+        eprintln!("loudgainer: --window can not be combined with --preview-seconds or --sidecar");
+        exit(2)
+    }
+    if matches!(opts.threads, Some(threads) if threads > MAX_THREADS) {
+        eprintln!("loudgainer: invalid number of threads (at most {MAX_THREADS})");
+        exit(2)
+    }
+    if let Some(seconds) = opts.preview_seconds {
+        if !seconds.is_finite() || seconds <= 0.0 {
+            eprintln!("loudgainer: invalid preview length (seconds)");
+            exit(2)
+        }
+    }
+    if let Some(lufs) = opts.reference_loudness_tag {
+        if !lufs.is_finite() {
+            eprintln!("loudgainer: invalid reference loudness tag (LUFS)");
+            exit(2)
+        }
     }
     let max_true_peak_level = if let Some(maxptl) = opts.maxtpl {
-        no_clip = true;
         if !maxptl.is_finite() {
-            panic!("Invalid max. true peak level (dBTP)");
+            eprintln!("loudgainer: invalid max. true peak level (dBTP)");
+            exit(2)
         }
         maxptl
     } else {
         -1.0
     };
 
+    let extensions: Vec<String> = match &opts.extensions {
+        Some(list) => list
+            .split(',')
+            .map(|ext| ext.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|ext| !ext.is_empty())
+            .collect(),
+        None => crate::files::EXTENSIONS.map(String::from).to_vec(),
+    };
+    if extensions.is_empty() {
+        eprintln!("loudgainer: invalid extension list");
+        exit(2)
+    }
+
+    let mut files = opts.files;
+    for playlist in &opts.playlist {
+        match crate::files::read_playlist(playlist) {
+            Ok(entries) => files.extend(entries),
+            Err(e) => {
+                eprintln!("loudgainer: can not read playlist {playlist}: {e}");
+                exit(2)
+            }
+        }
+    }
+
     Opts {
+        reference,
         pre_gain,
+        normalize_to: opts.normalize_to,
         max_true_peak_level,
-        warn_clip: !opts.clip,
-        clip_prevention: no_clip,
-        files: opts.files,
+        clip_mode,
+        files: if opts.recursive {
+            crate::files::expand(&files, &extensions)
+        } else {
+            files
+        },
+        oneline: opts.oneline,
         output: if opts.output {
             OutputMode::Old
         } else if opts.output_new {
             OutputMode::New
+        } else if opts.output_json {
+            OutputMode::Json
         } else {
             OutputMode::Human
         },
@@ -258,17 +774,110 @@ pub fn parse_arguments() -> Opts {
         } else {
             String::from("dB")
         },
+        output_unit: match opts.units {
+            Some(Units::Db) => String::from("dB"),
+            Some(Units::Lu) => String::from("LU"),
+            _ if opts.tagmode == Tagmode::L => String::from("LU"),
+            _ => String::from("dB"),
+        },
+        both_units: opts.units == Some(Units::Both),
+        precision: opts.precision.unwrap_or(2),
+        histogram: opts.histogram,
         mode: match opts.tagmode {
             Tagmode::D => Mode::Delete,
-            Tagmode::I => Mode::WriteExtended,
+            Tagmode::I => Mode::Write,
             Tagmode::E => Mode::WriteExtended,
-            Tagmode::L => Mode::Write,
+            Tagmode::L => Mode::WriteExtended,
             Tagmode::S => Mode::Noop,
         },
-        do_album: opts.album,
+        dry_run: opts.dry_run,
+        skip_tagged: opts.skip_tagged,
+        check: opts.check.then(|| opts.tolerance.unwrap_or(0.01)),
+        opus_output_gain: opts.opus_output_gain,
+        allow_experimental: opts.allow_experimental,
+        do_album: opts.album
+            || opts.per_directory_album
+            || opts.single_album
+            || opts.continuous_album,
+        // with recursive scanning every directory is its own album, unless told otherwise
+        per_directory_album: opts.per_directory_album
+            || (opts.recursive && opts.album && !opts.single_album),
+        continuous_album: opts.continuous_album,
+        strict: opts.strict,
+        true_peak: opts.tp_mode != Some(TpMode::Fast) && !opts.sample_peak_only,
+        preview_seconds: opts.preview_seconds,
+        window: opts.window.map(|Window(start, end)| (start, end)),
+        downmix: opts.downmix,
+        no_positive_gain: opts.no_positive_gain,
+        warn_lra: opts.warn_lra,
         lowercase: opts.lowercase,
+        keep_tags: opts.keep_loudness_tags,
         strip: opts.striptags,
+        atomic: opts.atomic,
         id3v2version: opts.id3v2version,
+        rva2: opts.rva2,
+        tag_format: opts.tag_type,
+        reference_loudness_tag: opts.reference_loudness_tag,
+        detailed: opts.detailed,
         quiet: opts.quiet,
+        cache: opts.cache,
+        sidecar: opts.sidecar,
+        // rayon picks one thread per CPU
+        threads: opts.threads.filter(|&threads| threads > 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagmode_parses_first_letter_case_insensitively() {
+        assert_eq!("i".parse::<Tagmode>().unwrap(), Tagmode::I);
+        assert_eq!("D".parse::<Tagmode>().unwrap(), Tagmode::D);
+    }
+
+    #[test]
+    fn tagmode_rejects_empty_and_unknown() {
+        let err = "".parse::<Tagmode>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid tag mode ''; expected one of d, i, e, l, s"
+        );
+        assert!("x".parse::<Tagmode>().is_err());
+    }
+
+    #[test]
+    fn opus_gain_parses_case_insensitively() {
+        assert_eq!("Track".parse::<OpusGain>().unwrap(), OpusGain::Track);
+        assert_eq!("album".parse::<OpusGain>().unwrap(), OpusGain::Album);
+        let err = "both".parse::<OpusGain>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid Opus output gain 'both'; expected track or album"
+        );
+    }
+
+    #[test]
+    fn ebur128_version_is_resolved() {
+        // cargo writes Cargo.lock before building, so the build script finds it
+        let version = env!("EBUR128_VERSION");
+        assert!(version.starts_with("0.1."), "{version}");
+    }
+
+    #[test]
+    fn clip_flags_map_to_modes() {
+        let table: [(&[&str], ClipMode); 6] = [
+            (&[], ClipMode::Warn),
+            (&["-c"], ClipMode::Ignore),
+            (&["-k"], ClipMode::Prevent),
+            (&["-K", "-2"], ClipMode::Prevent),
+            (&["-c", "-k"], ClipMode::Prevent),
+            (&["-c", "-K", "0"], ClipMode::Prevent),
+        ];
+        for (args, mode) in table {
+            let opts = MyOptions::parse_args_default(args).unwrap();
+            assert_eq!(opts.clip_mode(), mode, "{args:?}");
+        }
     }
 }
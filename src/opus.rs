@@ -0,0 +1,152 @@
+//! Output gain field of the Opus ID header (OpusHead, RFC 7845 section 5.1)
+//!
+//! Unlike R128_* comment tags, this gain is applied by every decoder. Changing it
+//! rewrites the first Ogg page of the file, so it modifies the bitstream (in place,
+//! the file size stays the same).
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::replay_gain::db_to_q78;
+
+/// Offset of output gain in OpusHead packet
+const GAIN_OFFSET: usize = 16;
+/// Offset of CRC in Ogg page header
+const CRC_OFFSET: usize = 22;
+/// Fixed part of Ogg page header, segment table follows
+const HEADER_LEN: usize = 27;
+
+/// Read output gain in dB
+pub fn read_output_gain<P: AsRef<Path>>(path: P) -> io::Result<f64> {
+    let mut file = File::open(path)?;
+    let (page, data) = read_head_page(&mut file)?;
+    let gain = i16::from_le_bytes([page[data + GAIN_OFFSET], page[data + GAIN_OFFSET + 1]]);
+    Ok(gain as f64 / 256.0)
+}
+
+/// Set output gain to `gain` dB (Q7.8, saturating)
+pub fn write_output_gain<P: AsRef<Path>>(path: P, gain: f64) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let (mut page, data) = read_head_page(&mut file)?;
+    let gain = db_to_q78(gain);
+    page[data + GAIN_OFFSET..data + GAIN_OFFSET + 2].copy_from_slice(&gain.to_le_bytes());
+    page[CRC_OFFSET..CRC_OFFSET + 4].fill(0);
+    let crc = ogg_crc(&page);
+    page[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&page)?;
+    file.flush()
+}
+
+/// Read first Ogg page, which must hold the OpusHead packet
+///
+/// Returns the whole page and the offset of the packet in it.
+fn read_head_page(file: &mut File) -> io::Result<(Vec<u8>, usize)> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut page = vec![0; HEADER_LEN];
+    file.read_exact(&mut page)?;
+    if &page[..4] != b"OggS" {
+        return Err(invalid("not an Ogg file"));
+    }
+    let segments = page[HEADER_LEN - 1] as usize;
+    page.resize(HEADER_LEN + segments, 0);
+    file.read_exact(&mut page[HEADER_LEN..])?;
+    let data_len: usize = page[HEADER_LEN..].iter().map(|&len| len as usize).sum();
+    let data = page.len();
+    page.resize(data + data_len, 0);
+    file.read_exact(&mut page[data..])?;
+    if data_len < GAIN_OFFSET + 2 || &page[data..data + 8] != b"OpusHead" {
+        return Err(invalid("no Opus ID header"));
+    }
+    Ok((page, data))
+}
+
+/// CRC-32 as used by Ogg (polynomial 0x04c11db7, no reflection, zero init)
+fn ogg_crc(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize]
+    })
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            r = if r & 0x8000_0000 != 0 {
+                (r << 1) ^ 0x04c1_1db7
+            } else {
+                r << 1
+            };
+            bit += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First page of a stereo 48 kHz Opus stream (stream serial 0x12345678), CRC zeroed
+    const HEAD_PAGE: [u8; 47] = [
+        b'O', b'g', b'g', b'S', 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0,
+        0, 0, 0, 0, 1, 19, b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', 1, 2, 0x38, 0x01, 0x80,
+        0xbb, 0, 0, 0, 0, 0,
+    ];
+    const HEAD_PAGE_CRC: u32 = 0x3eb0_ec23;
+
+    #[test]
+    fn ogg_crc_of_known_data() {
+        assert_eq!(ogg_crc(b"123456789"), 0x89a1_897f);
+        assert_eq!(ogg_crc(&HEAD_PAGE), HEAD_PAGE_CRC);
+    }
+
+    #[test]
+    fn output_gain_round_trip() {
+        let mut page = HEAD_PAGE.to_vec();
+        page[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&HEAD_PAGE_CRC.to_le_bytes());
+        // a following page is left alone
+        page.extend_from_slice(b"OggS rest");
+        let path =
+            std::env::temp_dir().join(format!("loudgainer-{}-head.opus", std::process::id()));
+        std::fs::write(&path, &page).unwrap();
+
+        assert_eq!(read_output_gain(&path).unwrap(), 0.0);
+        write_output_gain(&path, -6.5).unwrap();
+        let back = read_output_gain(&path).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(back, -6.5);
+        assert_eq!(written.len(), page.len());
+        assert_eq!(&written[HEAD_PAGE.len()..], b"OggS rest");
+        assert_eq!(
+            written[GAIN_OFFSET + 28..GAIN_OFFSET + 30],
+            (-1664i16).to_le_bytes()
+        );
+        // the stored CRC matches the new page
+        let mut head = written[..HEAD_PAGE.len()].to_vec();
+        let crc = u32::from_le_bytes(head[CRC_OFFSET..CRC_OFFSET + 4].try_into().unwrap());
+        head[CRC_OFFSET..CRC_OFFSET + 4].fill(0);
+        assert_eq!(crc, ogg_crc(&head));
+        assert_ne!(crc, HEAD_PAGE_CRC);
+    }
+
+    #[test]
+    fn read_output_gain_rejects_other_streams() {
+        let mut page = HEAD_PAGE;
+        page[HEADER_LEN + 1..HEADER_LEN + 9].copy_from_slice(b"OpusTags");
+        let path =
+            std::env::temp_dir().join(format!("loudgainer-{}-tags.opus", std::process::id()));
+        std::fs::write(&path, page).unwrap();
+        let err = read_output_gain(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Reports scanning progress as `[done/total] file` lines on stderr
+///
+/// Safe to tick from scanning threads; a disabled reporter only counts.
+pub struct Progress {
+    done: AtomicUsize,
+    total: usize,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, enabled: bool) -> Self {
+        Self {
+            done: AtomicUsize::new(0),
+            total,
+            enabled,
+        }
+    }
+
+    /// Count one finished file (scanned or skipped)
+    pub fn tick(&self, path: &str) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.enabled {
+            eprintln!("[{done}/{}] {path}", self.total);
+        }
+    }
+}
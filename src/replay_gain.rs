@@ -1,54 +1,338 @@
+use std::ops::ControlFlow;
 use std::path::Path;
 
 use ebur128::{EbuR128, Error, Mode};
 use log::{info, warn};
 
-use crate::audio::Audi;
+use crate::audio::{can_downmix, Audi, AudioError, AudioRef};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReplayGain {
     // This field must be
     pub gain: f64,
-    // This is optional in rg1
+    // This is optional in rg1; the peak that is tagged and used for clipping
     pub peak: f64,
+    // Measured peaks, `peak` is one of them (true peak is not measured in fast mode)
+    pub sample_peak: f64,
+    pub true_peak: Option<f64>,
     // This two are only used by loudgain
+    #[cfg_attr(feature = "serde", serde(rename = "range"))]
     pub loudness_range: f64,
+    #[cfg_attr(feature = "serde", serde(rename = "reference"))]
     pub loudness_reference: f64,
     // This field is not written to files
     pub loudness: f64,
+    // Set by clipper
+    pub will_clip: bool,
+    pub clip_prevented: bool,
+    // Max momentary (400 ms) and short-term (3 s) loudness in LUFS, only for detailed scans
+    pub momentary_max: Option<f64>,
+    pub short_term_max: Option<f64>,
+    // Only part of the audio was scanned (preview or window)
+    pub approximate: bool,
+    // Seconds of audio that were scanned
+    pub duration: f64,
+}
+
+/// What [ReplayGain::clipper] does when the gain would push the peak above the limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClipMode {
+    /// mark as clipping, log on info level only
+    Ignore,
+    /// mark as clipping and warn
+    #[default]
+    Warn,
+    /// lower the gain so the peak stays at the limit
+    Prevent,
+}
+
+/// How files are scanned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScanOptions {
+    /// loudness target in LUFS
+    pub reference: f64,
+    /// pre-gain in dB
+    pub pregain: f64,
+    /// also measure momentary and short-term loudness maxima
+    pub detailed: bool,
+    /// measure true peak (slow, oversampled); otherwise sample peak is used for `peak`
+    pub true_peak: bool,
+    /// stop after this many seconds of audio; results are marked approximate
+    pub preview: Option<f64>,
+    /// only measure audio from .0 to .1 seconds; results are marked approximate
+    pub window: Option<(f64, f64)>,
+    /// measure a stereo downmix of surround audio (peaks are those of the downmix)
+    pub downmix: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            reference: -18.0,
+            pregain: 0.0,
+            detailed: false,
+            true_peak: true,
+            preview: None,
+            window: None,
+            downmix: false,
+        }
+    }
 }
 
 impl ReplayGain {
-    pub fn display(&self, unit: &str) {
-        println!("Loudness: {:8.2} LUFS", self.loudness);
-        println!("Range: {:8.2} {unit}", self.loudness_range);
+    /// Gain for already measured values (LUFS, LU and linear peaks)
+    pub fn from_loudness(
+        loudness: f64,
+        loudness_range: f64,
+        sample_peak: f64,
+        true_peak: Option<f64>,
+        opts: &ScanOptions,
+    ) -> Self {
+        Self {
+            gain: gain_for(opts.reference, opts.pregain, loudness),
+            peak: true_peak.unwrap_or(sample_peak),
+            sample_peak,
+            true_peak,
+            loudness,
+            loudness_range,
+            loudness_reference: opts.reference + opts.pregain,
+            will_clip: false,
+            clip_prevented: false,
+            momentary_max: None,
+            short_term_max: None,
+            approximate: false,
+            duration: 0.0,
+        }
+    }
+
+    /// Unit of `peak` in dB: dBTP for true peak, dBFS for sample peak
+    pub fn peak_unit(&self) -> &'static str {
+        if self.true_peak.is_some() {
+            "dBTP"
+        } else {
+            "dBFS"
+        }
+    }
+
+    /// Print human-readable values; `unit` ("dB" or "LU") labels gains and ranges
+    pub fn display(&self, unit: &str, precision: usize) {
+        println!("Loudness: {:8.precision$} LUFS", self.loudness);
+        println!("Range: {:8.precision$} {unit}", self.loudness_range);
         println!(
-            "Peak: {:8.6} ({:8.6} dBTP)",
+            "Peak: {:8.6} ({:8.6} {})",
             self.peak,
-            lufs_to_dbtp(self.peak)
+            lufs_to_dbtp(self.peak),
+            self.peak_unit()
+        );
+        let note = if self.is_at_target() {
+            " (already at target)"
+        } else {
+            ""
+        };
+        println!("Gain: {:8.precision$} {unit}{note}", self.gain);
+        if self.approximate {
+            println!("Approximate: only part of the audio was scanned");
+        }
+    }
+
+    /// Print `name: gain -6.94 dB, peak 0.980000, -11.06 LUFS` (compact Human output)
+    pub fn display_oneline(&self, name: &str, unit: &str, precision: usize) {
+        println!(
+            "{name}: gain {:.precision$} {unit}, peak {:.6}, {:.precision$} LUFS",
+            self.gain, self.peak, self.loudness
         );
-        println!("Gain: {:8.2} {unit}", self.gain)
+    }
+
+    /// Same as [ReplayGain::display], plus loudness maxima and loudness after gain
+    pub fn display_detailed(&self, unit: &str, precision: usize) {
+        self.display(unit, precision);
+        if let Some(momentary_max) = self.momentary_max {
+            println!("Momentary max: {momentary_max:8.precision$} LUFS");
+        }
+        if let Some(short_term_max) = self.short_term_max {
+            println!("Short-term max: {short_term_max:8.precision$} LUFS");
+        }
+        println!(
+            "Effective loudness: {:8.precision$} LUFS",
+            self.effective_loudness()
+        );
+    }
+
+    /// Whether loudness is within 0.1 LU of the target, so the gain (about 0) changes nothing
+    pub fn is_at_target(&self) -> bool {
+        const AT_TARGET: f64 = 0.1;
+        (self.loudness - self.loudness_reference).abs() < AT_TARGET && self.gain.abs() < AT_TARGET
+    }
+
+    /// Loudness after gain is applied; equals `loudness_reference` unless gain was lowered
+    /// (clip prevention) or clamped
+    pub fn effective_loudness(&self) -> f64 {
+        self.loudness + self.gain
+    }
+
+    /// Print old-style mp3gain-compatible tab-delimited line
+    ///
+    /// We do not touch MP3 frames, so global_gain columns are always 0 (as in loudgain).
+    /// The MP3 gain column saturates at ±255 steps, as mp3gain does.
+    pub fn display_old(&self, file: &str) {
+        let file = escape_field(file);
+        println!(
+            "{file}\t{}\t{:.2}\t{:.2}\t{}\t{}",
+            db_to_mp3gain(self.gain),
+            self.gain,
+            self.peak * 32768.0,
+            0,
+            0
+        );
+    }
+
+    /// Print new-style tab-delimited line
+    ///
+    /// Tabs and line breaks in `file` are escaped (`\t`, `\n`, `\r`), so every line stays one row.
+    /// With `both_units`, range and gain are repeated in the other unit of dB and LU.
+    pub fn display_new(&self, file: &str, unit: &str, precision: usize, both_units: bool) {
+        let file = escape_field(file);
+        let peak_unit = self.peak_unit();
+        let other = self.other_unit_columns(unit, precision, both_units);
+        println!(
+            "{file}\t{:.precision$} LUFS\t{:.precision$} {unit}\t{:.6}\t{:.precision$} {peak_unit}\t{:.precision$} LUFS\t{}\t{}\t{:.precision$} {unit}\t{:.6}\t{:.precision$} {peak_unit}{other}",
+            self.loudness,
+            self.loudness_range,
+            self.peak,
+            lufs_to_dbtp(self.peak),
+            self.loudness_reference,
+            if self.will_clip { "Y" } else { "N" },
+            if self.clip_prevented { "Y" } else { "N" },
+            self.gain,
+            self.new_peak(),
+            lufs_to_dbtp(self.new_peak()),
+        );
+    }
+
+    /// Print new-style tab-delimited line, with max momentary/short-term and effective
+    /// loudness columns
+    pub fn display_new_detailed(&self, file: &str, unit: &str, precision: usize, both_units: bool) {
+        let file = escape_field(file);
+        let lufs =
+            |l: Option<f64>| l.map_or_else(|| "-".to_owned(), |l| format!("{l:.precision$} LUFS"));
+        let peak_unit = self.peak_unit();
+        let other = self.other_unit_columns(unit, precision, both_units);
+        println!(
+            "{file}\t{:.precision$} LUFS\t{:.precision$} {unit}\t{:.6}\t{:.precision$} {peak_unit}\t{:.precision$} LUFS\t{}\t{}\t{:.precision$} {unit}\t{:.6}\t{:.precision$} {peak_unit}\t{}\t{}\t{:.precision$} LUFS{other}",
+            self.loudness,
+            self.loudness_range,
+            self.peak,
+            lufs_to_dbtp(self.peak),
+            self.loudness_reference,
+            if self.will_clip { "Y" } else { "N" },
+            if self.clip_prevented { "Y" } else { "N" },
+            self.gain,
+            self.new_peak(),
+            lufs_to_dbtp(self.new_peak()),
+            lufs(self.momentary_max),
+            lufs(self.short_term_max),
+            self.effective_loudness(),
+        );
+    }
+
+    /// Range and gain columns in the unit that is not `unit` (1 LU equals 1 dB), if wanted
+    fn other_unit_columns(&self, unit: &str, precision: usize, wanted: bool) -> String {
+        if !wanted {
+            return String::new();
+        }
+        let other = other_unit(unit);
+        format!(
+            "\t{:.precision$} {other}\t{:.precision$} {other}",
+            self.loudness_range, self.gain
+        )
+    }
+
+    /// JSON object with all values
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "loudness": self.loudness,
+            "range": self.loudness_range,
+            "peak": self.peak,
+            "peak_dbtp": lufs_to_dbtp(self.peak),
+            "peak_unit": self.peak_unit(),
+            "sample_peak": self.sample_peak,
+            "true_peak": self.true_peak,
+            "gain": self.gain,
+            "reference": self.loudness_reference,
+            "will_clip": self.will_clip,
+            "clip_prevented": self.clip_prevented,
+            "momentary_max": self.momentary_max,
+            "short_term_max": self.short_term_max,
+            "effective_loudness": self.effective_loudness(),
+            "approximate": self.approximate,
+            "duration": self.duration,
+        })
+    }
+
+    /// Gain for Opus R128_*_GAIN tags: Q7.8 fixed point, relative to -23 LUFS (RFC 7845)
+    pub fn r128_gain(&self) -> i16 {
+        db_to_q78(self.gain + R128_REFERENCE - self.loudness_reference)
+    }
+
+    /// Values for audio that already has `db` applied (e.g. by the Opus header output gain)
+    pub fn with_applied_gain(&self, db: f64) -> Self {
+        let factor = dbtp_to_lufs(db);
+        Self {
+            gain: self.gain - db,
+            peak: self.peak * factor,
+            sample_peak: self.sample_peak * factor,
+            true_peak: self.true_peak.map(|peak| peak * factor),
+            loudness: self.loudness + db,
+            momentary_max: self.momentary_max.map(|l| l + db),
+            short_term_max: self.short_term_max.map(|l| l + db),
+            ..*self
+        }
+    }
+
+    /// Peak after gain is applied (linear, like [ReplayGain::peak])
+    pub fn new_peak(&self) -> f64 {
+        dbtp_to_lufs(self.gain) * self.peak
     }
 
     /// Detect clip and prevent it if requested
-    pub fn clipper(&self, max_true_peak_level: f64, warn: bool, prevent: bool) -> Self {
+    ///
+    /// `max_true_peak_level` is the ceiling in dBTP (user's `-K n`, -1.0 by default);
+    /// it may be positive. A new peak exactly at the ceiling does not count as clipping.
+    /// `name` (file or "Album") is only used for logging.
+    pub fn clipper(&self, name: &str, max_true_peak_level: f64, mode: ClipMode) -> Self {
+        // compared as linear amplitudes, as in loudgain: the gain (dB) is applied as
+        // factor 10^(gain/20) to the linear peak, the ceiling (dBTP) is converted alike
         let peak_limit = dbtp_to_lufs(max_true_peak_level);
-        // new peak after gain
-        let new_peak = dbtp_to_lufs(self.gain) * self.peak;
+        let new_peak = self.new_peak();
 
         if new_peak > peak_limit {
-            if prevent {
-                let new_new_peak = new_peak.min(peak_limit);
-                info!("Clipping prevented");
+            let unit = self.peak_unit();
+            let msg = format!(
+                "{name}: peak {:.2} {unit} is {:.2} {unit} after {:.2} dB gain, above the limit of {max_true_peak_level:.2} dBTP",
+                lufs_to_dbtp(self.peak),
+                lufs_to_dbtp(new_peak),
+                self.gain,
+            );
+            if mode == ClipMode::Prevent {
+                // dB by which the new peak exceeds the ceiling, so it ends up exactly at it
+                let reduction = lufs_to_dbtp(new_peak / peak_limit);
+                info!("{msg}; clipping prevented by lowering gain {reduction:.2} dB");
                 return Self {
-                    gain: self.gain - lufs_to_dbtp(new_peak / new_new_peak),
+                    gain: self.gain - reduction,
+                    will_clip: true,
+                    clip_prevented: true,
                     ..*self
                 };
-            } else if warn {
-                warn!("The track will clip!");
+            } else if mode == ClipMode::Warn {
+                warn!("{msg}; it will clip!");
             } else {
-                info!("The track will clip!");
+                info!("{msg}; it will clip!");
             }
+            return Self {
+                will_clip: true,
+                ..*self
+            };
         }
 
         *self
@@ -61,86 +345,852 @@ impl std::fmt::Display for ReplayGain {
         writeln!(f, "Range: {:8.2}", self.loudness_range)?;
         writeln!(
             f,
-            "Peak: {:8.6} ({:8.6} dBTP)",
+            "Peak: {:8.6} ({:8.6} {})",
             self.peak,
-            lufs_to_dbtp(self.peak)
+            lufs_to_dbtp(self.peak),
+            self.peak_unit()
         )?;
-        writeln!(f, "Gain: {:8.2}", self.gain)
+        writeln!(f, "Gain: {:8.2}", self.gain)?;
+        if self.approximate {
+            writeln!(f, "Approximate: only part of the audio was scanned")?;
+        }
+        Ok(())
     }
 }
 
-/// Calculates ReplayGain(2) with -18.00 LUFS
-pub fn track_rg<P: AsRef<Path>>(path: P, pregain: f64) -> Result<(ReplayGain, EbuR128), Error> {
-    use crate::audio::Audio;
+/// Calculates ReplayGain(2) for `reference` loudness target in LUFS (-18.00 LUFS for RG2)
+///
+/// `pregain` is in dB (same as LU) and is added to gain, so reference becomes `reference + pregain` LUFS
+pub fn track_rg<P: AsRef<Path>>(
+    path: P,
+    reference: f64,
+    pregain: f64,
+) -> Result<(ReplayGain, EbuR128), crate::Error> {
+    let opts = ScanOptions {
+        reference,
+        pregain,
+        ..Default::default()
+    };
+    track_rg_streaming(path, &opts)
+}
 
-    let audi = Audi::from_path(path);
-
-    // prepare ebur128
-    let mut e = EbuR128::new(
-        audi.channels,
-        audi.sample_rate as u32,
-        //Mode::S | Mode::I | Mode::LRA | Mode::TRUE_PEAK | Mode::SAMPLE_PEAK,
-        Mode::I | Mode::LRA | Mode::TRUE_PEAK,
-    )?;
-
-    match audi.audio {
-        Audio::S16(x) => e.add_frames_i16(&x)?,
-        Audio::S32(x) => e.add_frames_i32(&x)?,
-        Audio::F32(x) => e.add_frames_f32(&x)?,
-        Audio::F64(x) => e.add_frames_f64(&x)?,
-    }
-
-    let global = e.loudness_global()?;
-    let range = e.loudness_range()?;
-    let peak = (0..audi.channels)
-        .map(|i| e.true_peak(i).unwrap())
-        .reduce(f64::max)
-        .unwrap();
-
-    Ok((
-        ReplayGain {
-            gain: lufs_to_rg(global) + pregain,
-            peak,
-            loudness: global,
-            loudness_range: range,
-            loudness_reference: lufs_to_rg(-pregain),
-        },
-        e,
-    ))
+/// Same as [track_rg], but feeds EbuR128 packet by packet while decoding
+pub fn track_rg_streaming<P: AsRef<Path>>(
+    path: P,
+    opts: &ScanOptions,
+) -> Result<(ReplayGain, EbuR128), crate::Error> {
+    let mut meter: Option<Meter> = None;
+    Audi::stream_path(path, |info, samples| {
+        let meter = match &mut meter {
+            Some(meter) => meter,
+            None => meter.insert(Meter::new(info.channels, info.sample_rate, opts)?),
+        };
+        if meter.channels != info.channels {
+            return Err(AudioError::ChannelMismatch(meter.channels, info.channels).into());
+        }
+        Ok::<_, crate::Error>(meter.add_frames(samples)?)
+    })?;
+    // the sink is never called for a file without samples (e.g. an empty FLAC)
+    let meter = meter.ok_or(AudioError::MissingInfo("audio"))?;
+
+    Ok((meter.scan_rg(opts)?, meter.e))
+}
+
+/// Same as [track_rg], but decodes whole file into memory first
+pub fn track_rg_buffered<P: AsRef<Path>>(
+    path: P,
+    opts: &ScanOptions,
+) -> Result<(ReplayGain, EbuR128), crate::Error> {
+    let audi = Audi::from_path(path)?;
+    track_rg_from_audio(&audi, opts)
+}
+
+/// Same as [track_rg], for audio that is already decoded into memory
+///
+/// ```
+/// use loudgainer::audio::Audio;
+/// use loudgainer::{track_rg_from_audio, Audi, ScanOptions};
+///
+/// // 5 s of a 1 kHz sine at half scale, stereo
+/// let rate = 48000;
+/// let samples: Vec<f32> = (0..5 * rate)
+///     .map(|i| 0.5 * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / rate as f32).sin())
+///     .flat_map(|s| [s, s])
+///     .collect();
+/// let audi = Audi {
+///     audio: Audio::F32(samples),
+///     channels: 2,
+///     sample_rate: rate,
+///     bits: 32,
+/// };
+/// let (rg, _) = track_rg_from_audio(&audi, &ScanOptions::default()).unwrap();
+/// assert!((rg.loudness + 6.0).abs() < 0.1);
+/// assert!((rg.gain + 12.0).abs() < 0.1);
+/// ```
+pub fn track_rg_from_audio(
+    audi: &Audi,
+    opts: &ScanOptions,
+) -> Result<(ReplayGain, EbuR128), crate::Error> {
+    let mut meter = Meter::new(audi.channels, audi.sample_rate, opts)?;
+    // a preview or window just stops, there is nothing left to decode
+    let _ = meter.add_frames(audi.audio.samples())?;
+
+    Ok((meter.scan_rg(opts)?, meter.e))
+}
+
+/// EbuR128 plus maxima that can only be tracked while feeding it
+struct Meter {
+    e: EbuR128,
+    /// channels of the audio; EbuR128 has 2 when downmixing
+    channels: u32,
+    downmix: bool,
+    detailed: bool,
+    /// frames until the next 100 ms block ends (when momentary/short-term loudness update)
+    until_block: usize,
+    momentary_max: f64,
+    short_term_max: f64,
+    /// frames to drop before the window starts
+    until_start: usize,
+    /// frames until the preview or window ends
+    until_end: Option<usize>,
+    /// audio was cut off at the end
+    ended: bool,
+    /// audio was cut off at the start or end
+    approximate: bool,
+    /// frames fed so far
+    frames: u64,
+}
+
+impl Meter {
+    /// Fails for streams without channels or sample rate (broken metadata)
+    fn new(channels: u32, sample_rate: u32, opts: &ScanOptions) -> Result<Self, crate::Error> {
+        if channels == 0 || sample_rate == 0 {
+            return Err(AudioError::InvalidFormat(channels, sample_rate).into());
+        }
+        let mut mode = Mode::I | Mode::LRA | Mode::SAMPLE_PEAK;
+        if opts.true_peak {
+            mode |= Mode::TRUE_PEAK;
+        }
+        if opts.detailed {
+            mode |= Mode::M | Mode::S;
+        }
+        let frames = |seconds: f64| (seconds * sample_rate as f64).round() as usize;
+        let downmix = opts.downmix && channels > 2 && can_downmix(channels);
+        if opts.downmix && channels > 2 && !downmix {
+            warn!("Can not downmix {channels} channels, measuring all of them");
+        }
+        Ok(Self {
+            e: EbuR128::new(if downmix { 2 } else { channels }, sample_rate, mode)?,
+            channels,
+            downmix,
+            detailed: opts.detailed,
+            until_block: Self::block_frames(sample_rate),
+            momentary_max: f64::NEG_INFINITY,
+            short_term_max: f64::NEG_INFINITY,
+            until_start: opts.window.map_or(0, |(start, _)| frames(start)),
+            until_end: match (opts.window, opts.preview) {
+                (Some((start, end)), _) => Some(frames(end) - frames(start)),
+                (None, preview) => preview.map(frames),
+            },
+            ended: false,
+            approximate: false,
+            frames: 0,
+        })
+    }
+
+    fn block_frames(sample_rate: u32) -> usize {
+        (sample_rate as usize / 10).max(1)
+    }
+
+    /// Feed interleaved `samples`, breaks once audio past the preview or window end arrives
+    fn add_frames(&mut self, samples: AudioRef) -> Result<ControlFlow<()>, Error> {
+        let channels = self.channels as usize;
+        let mut samples = samples;
+        if self.until_start > 0 {
+            let skipped = self.until_start.min(samples.len() / channels);
+            samples = samples.slice(skipped * channels..samples.len());
+            self.until_start -= skipped;
+            self.approximate = true;
+        }
+        if let Some(until_end) = &mut self.until_end {
+            if samples.len() > *until_end * channels {
+                samples = samples.slice(0..*until_end * channels);
+                self.ended = true;
+                self.approximate = true;
+            }
+            *until_end -= samples.len() / channels;
+        }
+        if self.downmix {
+            // the layout was checked in new
+            let stereo = samples.downmix_stereo(self.channels).unwrap();
+            self.feed(AudioRef::from_f64(&stereo))?;
+        } else {
+            self.feed(samples)?;
+        }
+        Ok(if self.ended {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        })
+    }
+
+    fn feed(&mut self, samples: AudioRef) -> Result<(), Error> {
+        self.frames += (samples.len() / self.e.channels() as usize) as u64;
+        if !self.detailed {
+            return add_frames(&mut self.e, samples);
+        }
+        // feed up to each 100 ms block boundary, so no momentary/short-term value is missed
+        let channels = self.e.channels() as usize;
+        let mut start = 0;
+        while start < samples.len() {
+            let end = samples.len().min(start + self.until_block * channels);
+            add_frames(&mut self.e, samples.slice(start..end))?;
+            self.until_block -= (end - start) / channels;
+            if self.until_block == 0 {
+                self.momentary_max = self.momentary_max.max(self.e.loudness_momentary()?);
+                self.short_term_max = self.short_term_max.max(self.e.loudness_shortterm()?);
+                self.until_block = Self::block_frames(self.e.rate());
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Read results out of filled EbuR128
+    ///
+    /// Peaks are the maximum over all channels EbuR128 was set up with (LFE included).
+    /// Fails when a window was given but the audio ended before any of it.
+    fn scan_rg(&self, opts: &ScanOptions) -> Result<ReplayGain, crate::Error> {
+        let e = &self.e;
+        if let (Some((start, _)), 0) = (opts.window, self.frames) {
+            // nothing was fed, so every frame seen went to skipping
+            let skipped = (start * e.rate() as f64).round() as usize - self.until_start;
+            return Err(AudioError::WindowPastEnd(skipped as f64 / e.rate() as f64).into());
+        }
+        let global = e.loudness_global()?;
+        let range = e.loudness_range()?;
+        let mut true_peak = opts.true_peak.then_some(0.0_f64);
+        let mut sample_peak = 0.0_f64;
+        for i in 0..e.channels() {
+            if let Some(peak) = &mut true_peak {
+                *peak = peak.max(e.true_peak(i)?);
+            }
+            sample_peak = sample_peak.max(e.sample_peak(i)?);
+        }
+
+        Ok(ReplayGain {
+            momentary_max: self.detailed.then_some(self.momentary_max),
+            short_term_max: self.detailed.then_some(self.short_term_max),
+            approximate: self.approximate,
+            duration: self.frames as f64 / e.rate() as f64,
+            ..ReplayGain::from_loudness(global, range, sample_peak, true_peak, opts)
+        })
+    }
+}
+
+fn add_frames(e: &mut EbuR128, samples: AudioRef) -> Result<(), Error> {
+    match samples {
+        AudioRef::S16(x) => e.add_frames_i16(x),
+        AudioRef::S32(x) => e.add_frames_i32(x),
+        AudioRef::F32(x) => e.add_frames_f32(x),
+        AudioRef::F64(x) => e.add_frames_f64(x),
+    }
 }
 
-pub fn album_rg(scans: &[(ReplayGain, EbuR128)], pregain: f64) -> Result<ReplayGain, Error> {
+/// Distinct (channels, sample rate) of album tracks; combining more than one is questionable
+///
+/// Take the formats before [compact_state], which loses the sample rate.
+pub fn album_formats(formats: impl IntoIterator<Item = (u32, u32)>) -> Vec<(u32, u32)> {
+    let mut formats: Vec<(u32, u32)> = formats.into_iter().collect();
+    formats.sort_unstable();
+    formats.dedup();
+    formats
+}
+
+/// Free what album gain does not need from the EbuR128 of a scanned track
+///
+/// Album loudness and range need every gating block of every track (the relative gate
+/// depends on all of them), so states can not be merged into a running total. The audio
+/// buffer (3 s of every channel, MBs for surround) and filters are only needed while
+/// feeding, so they are reallocated at the lowest sample rate; block histories are kept.
+/// Peaks and the sample rate are lost, further frames must not be added.
+pub fn compact_state(e: &mut EbuR128) -> Result<(), Error> {
+    const MIN_RATE: u32 = 16;
+    e.change_parameters(e.channels(), MIN_RATE)
+}
+
+/// Same as [album_rg], but takes the states and compacts them one by one
+///
+/// With a lazy iterator (e.g. scanning tracks in order), only one complete EbuR128 is
+/// alive at a time.
+pub fn album_rg_iter(
+    scans: impl IntoIterator<Item = (ReplayGain, EbuR128)>,
+    reference: f64,
+    pregain: f64,
+) -> Result<ReplayGain, crate::Error> {
+    let scans = scans
+        .into_iter()
+        .map(|(rg, mut e)| {
+            compact_state(&mut e)?;
+            Ok((rg, e))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    album_rg(&scans, reference, pregain)
+}
+
+pub fn album_rg(
+    scans: &[(ReplayGain, EbuR128)],
+    reference: f64,
+    pregain: f64,
+) -> Result<ReplayGain, crate::Error> {
+    // for a one-track album these equal the track's loudness and range (no special case
+    // needed, the _multiple variants accept a single state)
     let global = EbuR128::loudness_global_multiple(scans.iter().map(|(_, e)| e))?;
     let range = EbuR128::loudness_range_multiple(scans.iter().map(|(_, e)| e))?;
 
-    let peak = scans
-        .iter()
-        .map(|(rg, _)| rg.peak)
-        .reduce(f64::max)
-        .unwrap();
+    let tracks: Vec<&ReplayGain> = scans.iter().map(|(rg, _)| rg).collect();
+    Ok(album_from_tracks(
+        &tracks, global, range, reference, pregain,
+    ))
+}
 
-    Ok(ReplayGain {
-        gain: lufs_to_rg(global) + pregain,
-        peak,
+/// Album result as if `paths` were played as one continuous (gapless) stream
+///
+/// Instead of combining the states of [album_rg], a single EbuR128 is fed with every track
+/// in order, so gating blocks span track boundaries (live albums, DJ mixes). This decodes
+/// all tracks once more; `tracks` (their scans, in the same order) give peaks and duration.
+/// All tracks must have the same channel count and sample rate.
+pub fn album_rg_continuous<P: AsRef<Path>>(
+    paths: &[P],
+    tracks: &[ReplayGain],
+    opts: &ScanOptions,
+) -> Result<ReplayGain, crate::Error> {
+    // preview and window would cut the stream, maxima come from the tracks
+    let stream_opts = ScanOptions {
+        detailed: false,
+        true_peak: false,
+        preview: None,
+        window: None,
+        ..*opts
+    };
+    let mut meter: Option<Meter> = None;
+    for path in paths {
+        Audi::stream_path(path, |info, samples| {
+            let meter = match &mut meter {
+                Some(meter) => meter,
+                None => meter.insert(Meter::new(info.channels, info.sample_rate, &stream_opts)?),
+            };
+            if meter.channels != info.channels {
+                return Err(AudioError::ChannelMismatch(meter.channels, info.channels).into());
+            }
+            if meter.e.rate() != info.sample_rate {
+                return Err(AudioError::RateMismatch(meter.e.rate(), info.sample_rate).into());
+            }
+            Ok::<_, crate::Error>(meter.add_frames(samples)?)
+        })?;
+    }
+    let meter = meter.ok_or(AudioError::MissingInfo("audio"))?;
+    let global = meter.e.loudness_global()?;
+    let range = meter.e.loudness_range()?;
+    let tracks: Vec<&ReplayGain> = tracks.iter().collect();
+    Ok(album_from_tracks(
+        &tracks,
+        global,
+        range,
+        opts.reference,
+        opts.pregain,
+    ))
+}
+
+/// Album of `tracks` (at least one) with loudness `global` and `range`; peaks are the
+/// highest of the tracks, durations add up
+fn album_from_tracks(
+    tracks: &[&ReplayGain],
+    global: f64,
+    range: f64,
+    reference: f64,
+    pregain: f64,
+) -> ReplayGain {
+    let max_peak =
+        |f: fn(&ReplayGain) -> f64| tracks.iter().map(|rg| f(rg)).reduce(f64::max).unwrap();
+    // only present if every track has it
+    let max_detail = |f: fn(&ReplayGain) -> Option<f64>| {
+        tracks
+            .iter()
+            .map(|rg| f(rg))
+            .reduce(|a, b| Some(a?.max(b?)))
+            .flatten()
+    };
+
+    ReplayGain {
+        gain: gain_for(reference, pregain, global),
+        peak: max_peak(|rg| rg.peak),
+        sample_peak: max_peak(|rg| rg.sample_peak),
+        true_peak: max_detail(|rg| rg.true_peak),
         loudness: global,
         loudness_range: range,
-        loudness_reference: lufs_to_rg(-pregain),
-    })
+        loudness_reference: reference + pregain,
+        will_clip: false,
+        clip_prevented: false,
+        momentary_max: max_detail(|rg| rg.momentary_max),
+        short_term_max: max_detail(|rg| rg.short_term_max),
+        approximate: tracks.iter().any(|rg| rg.approximate),
+        duration: tracks.iter().map(|rg| rg.duration).sum(),
+    }
+}
+
+/// "LU" for "dB" and the other way round
+pub fn other_unit(unit: &str) -> &'static str {
+    if unit == "LU" {
+        "dB"
+    } else {
+        "LU"
+    }
 }
 
+/// Escape `field` for tab-delimited output
+///
+/// Backslashes are escaped too (so Windows paths show `\\`), else a literal `\t` in a
+/// file name would read back as a tab.
+fn escape_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if !field.contains(['\\', '\t', '\n', '\r']) {
+        return field.into();
+    }
+    let mut escaped = String::with_capacity(field.len() + 2);
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.into()
+}
+
+/// Loudness that Opus R128 gains are relative to
+const R128_REFERENCE: f64 = -23.0;
+
 #[inline]
-pub(crate) fn lufs_to_rg(l: f64) -> f64 {
-    -18.0 - l
+/// Gain in dB needed to bring loudness `l` to `reference` (both in LUFS)
+///
+/// No pre-gain or silence handling; -inf loudness gives an infinite gain.
+pub fn lufs_to_rg(reference: f64, l: f64) -> f64 {
+    reference - l
+}
+
+/// Gain to bring `loudness` to `reference` + `pregain`
+///
+/// Silence has no loudness (-inf LUFS), so there is nothing to correct: it gets 0 dB
+/// instead of an infinite gain.
+fn gain_for(reference: f64, pregain: f64, loudness: f64) -> f64 {
+    if loudness.is_finite() {
+        lufs_to_rg(reference, loudness) + pregain
+    } else {
+        warn!("No loudness measured (silence?), using 0 dB gain");
+        0.0
+    }
 }
 
 #[inline]
-/// The equation to convert to dBTP is: 20 * log10(n)
-pub(crate) fn lufs_to_dbtp(n: f64) -> f64 {
+/// Linear amplitude `n` (peak; 1.0 is full scale) in dB (dBTP/dBFS): 20 * log10(n)
+///
+/// Despite the name the input is not in LUFS. 0 gives -inf, negative values NaN.
+pub fn lufs_to_dbtp(n: f64) -> f64 {
     20.0 * (n).log10()
 }
 
 #[inline]
-/// The equation to convert to LUFS is: 10 ** (n / 20.0)
-pub(crate) fn dbtp_to_lufs(n: f64) -> f64 {
+/// Level `n` in dB as linear amplitude (factor): 10 ** (n / 20.0)
+///
+/// Inverse of [lufs_to_dbtp] for positive amplitudes; also turns a gain into the factor
+/// it scales samples by.
+pub fn dbtp_to_lufs(n: f64) -> f64 {
     10.0_f64.powf(n / 20.0)
 }
+
+#[inline]
+/// mp3gain applies gain in steps of 1.5 dB (exactly 5 * log10(2)) to the 8 bit global_gain
+/// of MP3 frames, so more than 255 steps either way saturates every frame
+pub(crate) fn db_to_mp3gain(db: f64) -> i32 {
+    (db / (5.0 * 2.0_f64.log10())).round().clamp(-255.0, 255.0) as i32
+}
+
+#[inline]
+/// Q7.8 fixed point (1/256 dB steps), saturating at i16 bounds
+pub(crate) fn db_to_q78(db: f64) -> i16 {
+    (db * 256.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::Audio;
+
+    /// Track at `loudness` LUFS with true peak `peak`, -18 LUFS reference
+    fn track(loudness: f64, peak: f64) -> ReplayGain {
+        ReplayGain::from_loudness(loudness, 0.0, peak, Some(peak), &ScanOptions::default())
+    }
+
+    /// `seconds` of a 1 kHz stereo sine at 48 kHz with amplitude `amplitude`
+    fn sine(seconds: f64, amplitude: f32) -> Audi {
+        let rate = 48000;
+        let frames = (seconds * rate as f64) as usize;
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let x = amplitude
+                    * (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / rate as f32).sin();
+                [x, x]
+            })
+            .collect();
+        Audi {
+            audio: Audio::F32(samples),
+            channels: 2,
+            sample_rate: rate,
+            bits: 32,
+        }
+    }
+
+    /// Write `audi` (F32 samples) as a 16 bit WAV file `name` in the temp dir
+    fn write_wav(name: &str, audi: &Audi) -> std::path::PathBuf {
+        let Audio::F32(samples) = &audi.audio else {
+            unreachable!()
+        };
+        let data_len = samples.len() as u32 * 2;
+        let block_align = audi.channels as u16 * 2;
+        let mut wav = Vec::with_capacity(44 + data_len as usize);
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&(audi.channels as u16).to_le_bytes());
+        wav.extend_from_slice(&audi.sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(audi.sample_rate * block_align as u32).to_le_bytes());
+        wav.extend_from_slice(&block_align.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for x in samples {
+            wav.extend_from_slice(&((x * i16::MAX as f32) as i16).to_le_bytes());
+        }
+        let path = std::env::temp_dir().join(format!("loudgainer-{}-{name}", std::process::id()));
+        std::fs::write(&path, wav).unwrap();
+        path
+    }
+
+    #[test]
+    fn streaming_equals_buffered() {
+        let path = write_wav("streaming.wav", &sine(3.0, 0.5));
+        let opts = ScanOptions::default();
+        let (streamed, _) = track_rg_streaming(&path, &opts).unwrap();
+        let (buffered, _) = track_rg_buffered(&path, &opts).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(streamed, buffered);
+        assert!((streamed.loudness + 6.0).abs() < 0.1);
+        assert!((streamed.duration - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn continuous_album_is_close_to_combined_states() {
+        let paths = [
+            write_wav("continuous-1.wav", &sine(3.0, 0.5)),
+            write_wav("continuous-2.wav", &sine(3.0, 0.25)),
+        ];
+        let opts = ScanOptions::default();
+        let scans: Vec<_> = paths
+            .iter()
+            .map(|path| track_rg_streaming(path, &opts).unwrap())
+            .collect();
+        let tracks: Vec<ReplayGain> = scans.iter().map(|(rg, _)| *rg).collect();
+        let continuous = album_rg_continuous(&paths, &tracks, &opts).unwrap();
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        let combined = album_rg(&scans, opts.reference, opts.pregain).unwrap();
+        // only the gating blocks that span the track boundary differ
+        assert!((continuous.loudness - combined.loudness).abs() < 0.1);
+        assert!((continuous.gain - combined.gain).abs() < 0.1);
+        assert_eq!(continuous.peak, tracks[0].peak);
+        assert_eq!(continuous.true_peak, combined.true_peak);
+        assert!((continuous.duration - 6.0).abs() < 1e-9);
+        // louder than the quiet track, quieter than the loud one
+        assert!(continuous.loudness < tracks[0].loudness);
+        assert!(continuous.loudness > tracks[1].loudness);
+    }
+
+    #[test]
+    fn compacted_states_give_the_same_album() {
+        let opts = ScanOptions::default();
+        let scan = || {
+            [sine(3.0, 0.5), sine(2.0, 0.1)]
+                .iter()
+                .map(|audi| track_rg_from_audio(audi, &opts).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let album = album_rg(&scan(), opts.reference, opts.pregain).unwrap();
+        let compacted = album_rg_iter(scan(), opts.reference, opts.pregain).unwrap();
+        assert_eq!(compacted, album);
+    }
+
+    #[test]
+    fn one_track_album_equals_the_track() {
+        // a louder second half, so the range is not 0
+        let mut audi = sine(4.0, 0.1);
+        let Audio::F32(samples) = &mut audi.audio else {
+            unreachable!()
+        };
+        let half = samples.len() / 2;
+        samples[half..].iter_mut().for_each(|x| *x *= 5.0);
+        let opts = ScanOptions::default();
+        let scans = [track_rg_from_audio(&audi, &opts).unwrap()];
+        let track = scans[0].0;
+        assert!(track.loudness_range > 1.0);
+        let album = album_rg(&scans, opts.reference, opts.pregain).unwrap();
+        assert_eq!(album.loudness_range, track.loudness_range);
+        assert_eq!(album.loudness, track.loudness);
+        assert_eq!(album.gain, track.gain);
+        assert_eq!(album.peak, track.peak);
+    }
+
+    #[test]
+    fn preview_stops_and_marks_approximate() {
+        let audi = sine(5.0, 0.5);
+        let (full, _) = track_rg_from_audio(&audi, &ScanOptions::default()).unwrap();
+        assert!(!full.approximate);
+        assert!((full.duration - 5.0).abs() < 1e-9);
+
+        let preview = |seconds| ScanOptions {
+            preview: Some(seconds),
+            ..Default::default()
+        };
+        let (rg, _) = track_rg_from_audio(&audi, &preview(2.0)).unwrap();
+        assert!(rg.approximate);
+        assert!((rg.duration - 2.0).abs() < 1e-9);
+        assert!((rg.loudness - full.loudness).abs() < 0.1);
+        // fed in 1 s packets, decoding stops at the first one past the preview
+        let mut meter = Meter::new(2, 48000, &preview(2.0)).unwrap();
+        let samples = audi.audio.samples();
+        let flow: Vec<_> = (0..5)
+            .map(|i| {
+                meter
+                    .add_frames(samples.slice(i * 96000..(i + 1) * 96000))
+                    .unwrap()
+            })
+            .take_while(|flow| flow.is_continue())
+            .collect();
+        assert_eq!(flow.len(), 2);
+        assert_eq!(meter.frames, 96000);
+        // nothing is cut off when the preview is longer than the audio
+        let (rg, _) = track_rg_from_audio(&audi, &preview(10.0)).unwrap();
+        assert!(!rg.approximate);
+        assert_eq!(rg, full);
+    }
+
+    #[test]
+    fn window_past_the_end_is_an_error() {
+        let audi = sine(2.0, 0.5);
+        for start in [2.0, 5.0] {
+            let opts = ScanOptions {
+                window: Some((start, start + 1.0)),
+                ..Default::default()
+            };
+            match track_rg_from_audio(&audi, &opts) {
+                Err(crate::Error::Audio(AudioError::WindowPastEnd(duration))) => {
+                    assert!((duration - 2.0).abs() < 1e-9)
+                }
+                result => panic!("window at {start} s gave {:?}", result.map(|r| r.0)),
+            }
+        }
+    }
+
+    #[test]
+    fn window_cuts_both_ends() {
+        let opts = ScanOptions {
+            window: Some((1.0, 3.0)),
+            ..Default::default()
+        };
+        let (rg, _) = track_rg_from_audio(&sine(5.0, 0.5), &opts).unwrap();
+        assert!(rg.approximate);
+        assert!((rg.duration - 2.0).abs() < 1e-9);
+        assert!((rg.loudness + 6.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn gain_brings_loudness_to_reference() {
+        for reference in [-18.0, -23.0, -14.0] {
+            assert_eq!(lufs_to_rg(reference, -10.0), reference + 10.0);
+            let opts = ScanOptions {
+                reference,
+                ..Default::default()
+            };
+            let rg = ReplayGain::from_loudness(-10.0, 0.0, 0.5, None, &opts);
+            assert_eq!(rg.gain, reference + 10.0);
+            assert_eq!(rg.loudness_reference, reference);
+            assert_eq!(rg.effective_loudness(), reference);
+        }
+        // pre-gain moves the target too
+        let opts = ScanOptions {
+            reference: -23.0,
+            pregain: 3.0,
+            ..Default::default()
+        };
+        let rg = ReplayGain::from_loudness(-10.0, 0.0, 0.5, None, &opts);
+        assert_eq!(rg.gain, -10.0);
+        assert_eq!(rg.loudness_reference, -20.0);
+    }
+
+    #[test]
+    fn silence_gets_0_db_gain() {
+        assert_eq!(gain_for(-18.0, 3.0, f64::NEG_INFINITY), 0.0);
+        assert_eq!(gain_for(-18.0, 0.0, f64::NAN), 0.0);
+
+        let (rg, _) = track_rg_from_audio(&sine(1.0, 0.0), &ScanOptions::default()).unwrap();
+        assert_eq!(rg.loudness, f64::NEG_INFINITY);
+        assert_eq!(rg.gain, 0.0);
+        assert_eq!(rg.peak, 0.0);
+        let rg = rg.clipper("test", -1.0, ClipMode::Prevent);
+        assert!(!rg.will_clip);
+        assert!(rg.gain.is_finite() && rg.new_peak().is_finite());
+    }
+
+    #[test]
+    fn effective_loudness_is_at_reference() {
+        // (loudness, peak, prevent clipping, effective loudness)
+        let table = [
+            (-30.0, 0.1, false, -18.0),
+            (-6.0, 0.5, false, -18.0),
+            (-18.0, 1.0, false, -18.0),
+            // +12 dB would clip; with prevention only 6 dB are applied
+            (-30.0, 0.5, false, -18.0),
+            (-30.0, 0.5, true, -30.0 + lufs_to_dbtp(2.0)),
+        ];
+        for (loudness, peak, prevent, effective) in table {
+            let mode = if prevent {
+                ClipMode::Prevent
+            } else {
+                ClipMode::Warn
+            };
+            let rg = track(loudness, peak).clipper("test", 0.0, mode);
+            assert!(
+                (rg.effective_loudness() - effective).abs() < 0.01,
+                "{loudness} LUFS ended up at {}",
+                rg.effective_loudness()
+            );
+        }
+    }
+
+    #[test]
+    fn at_target_within_0_1_lu() {
+        let table = [
+            (-18.0, true),
+            (-18.05, true),
+            (-17.95, true),
+            (-18.1, false),
+            (-17.5, false),
+            (-6.0, false),
+            (f64::NEG_INFINITY, false),
+        ];
+        for (loudness, at_target) in table {
+            assert_eq!(track(loudness, 0.5).is_at_target(), at_target, "{loudness}");
+        }
+    }
+
+    #[test]
+    fn clipper_lowers_gain_to_ceiling_of_0_dbtp() {
+        // +12 dB on a peak of 0.5 would end up at about +6 dBTP
+        let rg = track(-30.0, 0.5).clipper("test", 0.0, ClipMode::Prevent);
+        assert!(rg.will_clip && rg.clip_prevented);
+        assert!((rg.gain - lufs_to_dbtp(2.0)).abs() < 1e-9);
+        assert!((rg.new_peak() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clipper_keeps_gain_without_prevention() {
+        let rg = track(-30.0, 0.5).clipper("test", 0.0, ClipMode::Warn);
+        assert!(rg.will_clip && !rg.clip_prevented);
+        assert_eq!(rg.gain, 12.0);
+    }
+
+    #[test]
+    fn clip_modes_mark_and_prevent() {
+        // (mode, will_clip, clip_prevented, gain)
+        let table = [
+            (ClipMode::Ignore, true, false, 12.0),
+            (ClipMode::Warn, true, false, 12.0),
+            (ClipMode::Prevent, true, true, lufs_to_dbtp(2.0)),
+        ];
+        for (mode, will_clip, clip_prevented, gain) in table {
+            let rg = track(-30.0, 0.5).clipper("test", 0.0, mode);
+            assert_eq!(
+                (rg.will_clip, rg.clip_prevented),
+                (will_clip, clip_prevented)
+            );
+            assert!((rg.gain - gain).abs() < 1e-9, "{mode:?}");
+            // nothing to do below the limit
+            assert_eq!(
+                track(-18.0, 0.5).clipper("test", 0.0, mode),
+                track(-18.0, 0.5)
+            );
+        }
+    }
+
+    #[test]
+    fn clipper_accepts_peak_at_ceiling() {
+        // 0 dB gain on a full scale peak is exactly at 0 dBTP
+        let rg = track(-18.0, 1.0).clipper("test", 0.0, ClipMode::Prevent);
+        assert!(!rg.will_clip && !rg.clip_prevented);
+        assert_eq!(rg.gain, 0.0);
+    }
+
+    #[test]
+    fn q78_rounds_and_saturates() {
+        assert_eq!(db_to_q78(0.0), 0);
+        assert_eq!(db_to_q78(1.0), 256);
+        assert_eq!(db_to_q78(-5.3), -1357);
+        assert_eq!(db_to_q78(200.0), i16::MAX);
+        assert_eq!(db_to_q78(-200.0), i16::MIN);
+    }
+
+    #[test]
+    fn escape_field_keeps_plain_names() {
+        assert!(matches!(
+            escape_field("music/song.flac"),
+            std::borrow::Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn escape_field_escapes_backslash_first() {
+        assert_eq!(escape_field("a\tb\nc\rd"), "a\\tb\\nc\\rd");
+        assert_eq!(
+            escape_field("C:\\music\\song.flac"),
+            "C:\\\\music\\\\song.flac"
+        );
+        // a literal backslash-t is told apart from an escaped tab
+        assert_eq!(escape_field("a\\tb"), "a\\\\tb");
+        assert_ne!(escape_field("a\\tb"), escape_field("a\tb"));
+    }
+
+    #[test]
+    fn mp3gain_steps_saturate_at_255() {
+        assert_eq!(db_to_mp3gain(0.0), 0);
+        assert_eq!(db_to_mp3gain(3.0), 2);
+        assert_eq!(db_to_mp3gain(-4.6), -3);
+        assert_eq!(db_to_mp3gain(1000.0), 255);
+        assert_eq!(db_to_mp3gain(-1000.0), -255);
+    }
+
+    #[test]
+    fn dbtp_and_linear_round_trip() {
+        for x in [1e-6, 0.001, 0.25, 0.5, 1.0, 2.0, 1e3] {
+            let back = dbtp_to_lufs(lufs_to_dbtp(x));
+            assert!((back - x).abs() <= x * 1e-12, "{x} came back as {back}");
+        }
+        for db in [-120.0, -18.0, -1.0, 0.0, 6.0] {
+            assert!((lufs_to_dbtp(dbtp_to_lufs(db)) - db).abs() < 1e-9);
+        }
+        assert_eq!(lufs_to_dbtp(0.0), f64::NEG_INFINITY);
+    }
+}
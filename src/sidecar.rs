@@ -0,0 +1,113 @@
+use std::fs;
+
+use log::{debug, warn};
+use serde_json::Value;
+
+use loudgainer::replay_gain::{ReplayGain, ScanOptions};
+
+/// Sidecar names tried for `song.flac`: `song.flac.lufs`, then `song.flac.json`
+const EXTENSIONS: [&str; 2] = ["lufs", "json"];
+
+/// Measured values of `file` from its sidecar, if there is a valid one
+///
+/// A sidecar is a JSON object with `loudness` (LUFS) and optional `range` (LU) and
+/// `duration` (s). Peaks (linear) are read from `true_peak` and `sample_peak`, with `peak`
+/// as fallback for both; at least one of them is required. Track objects of `-J` output
+/// are valid sidecars.
+pub fn read(file: &str, opts: &ScanOptions) -> Option<ReplayGain> {
+    let (sidecar, bytes) = EXTENSIONS.iter().find_map(|ext| {
+        let sidecar = format!("{file}.{ext}");
+        fs::read(&sidecar).ok().map(|bytes| (sidecar, bytes))
+    })?;
+    let entry: Value = match serde_json::from_slice(&bytes) {
+        Ok(entry) => entry,
+        Err(e) => {
+            warn!("Ignoring invalid sidecar {sidecar}: {e}");
+            return None;
+        }
+    };
+    let value = |key: &str| entry.get(key).and_then(Value::as_f64);
+    let values = value("loudness").and_then(|loudness| {
+        let peak = value("peak");
+        let sample_peak = value("sample_peak").or(peak);
+        let true_peak = if opts.true_peak {
+            value("true_peak").or(peak)
+        } else {
+            None
+        };
+        Some((loudness, sample_peak.or(true_peak)?, true_peak))
+    });
+    let (loudness, sample_peak, true_peak) = match values {
+        Some(values) => values,
+        None => {
+            warn!("Ignoring sidecar {sidecar}: loudness or peak missing");
+            return None;
+        }
+    };
+    debug!("Using loudness of {file} from {sidecar}");
+    Some(ReplayGain {
+        duration: value("duration").unwrap_or(0.0),
+        ..ReplayGain::from_loudness(
+            loudness,
+            value("range").unwrap_or(0.0),
+            sample_peak,
+            true_peak,
+            opts,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read sidecar `{name}.lufs` with `contents`
+    fn read_sidecar(name: &str, contents: &str, opts: &ScanOptions) -> Option<ReplayGain> {
+        let file = std::env::temp_dir()
+            .join(format!("loudgainer-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let sidecar = format!("{file}.lufs");
+        fs::write(&sidecar, contents).unwrap();
+        let rg = read(&file, opts);
+        fs::remove_file(&sidecar).unwrap();
+        rg
+    }
+
+    #[test]
+    fn sidecar_gives_scan_values() {
+        let opts = ScanOptions::default();
+        let json = r#"{"loudness": -11.0, "range": 4.5, "true_peak": 0.95, "sample_peak": 0.9, "duration": 180.0}"#;
+        let rg = read_sidecar("full.flac", json, &opts).unwrap();
+        let scanned = ReplayGain {
+            duration: 180.0,
+            ..ReplayGain::from_loudness(-11.0, 4.5, 0.9, Some(0.95), &opts)
+        };
+        assert_eq!(rg, scanned);
+        // -J output of a scan reads back as that scan
+        let json = scanned.json().to_string();
+        assert_eq!(read_sidecar("json.flac", &json, &opts), Some(scanned));
+        // `peak` stands in for both peaks
+        let rg = read_sidecar("peak.flac", r#"{"loudness": -11.0, "peak": 0.5}"#, &opts).unwrap();
+        assert_eq!((rg.sample_peak, rg.true_peak), (0.5, Some(0.5)));
+    }
+
+    #[test]
+    fn malformed_sidecar_is_ignored() {
+        let opts = ScanOptions::default();
+        for contents in [
+            r#"{"loudness": -11.0, "peak": 0.5"#,
+            "loudness=-11.0",
+            r#"{"loudness": "-11.0", "peak": 0.5}"#,
+            r#"{"loudness": -11.0}"#,
+            r#"{"peak": 0.5}"#,
+            "",
+        ] {
+            assert_eq!(
+                read_sidecar("bad.flac", contents, &opts),
+                None,
+                "{contents}"
+            );
+        }
+    }
+}
@@ -1,9 +1,14 @@
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use lofty::{Probe, TagType, TaggedFile};
+use id3::frame::{Content, ExtendedText, Frame, Unknown};
+use id3::TagLike;
+use lofty::{FileType, ItemKey, ItemValue, Probe, Tag, TagExt, TagItem, TagType, TaggedFile};
 use log::warn;
 
-use crate::options::Id3v2version;
+use crate::audio::extension;
+use crate::mp4;
 use crate::replay_gain::ReplayGain;
 
 const TAGS: [&str; 9] = [
@@ -20,53 +25,585 @@ const TAGS: [&str; 9] = [
 
 // this is where we store the RG tags in MP4/M4A files
 const RG_ATOM: &str = "----:com.apple.iTunes:";
+/// `mean` of [RG_ATOM]
+const RG_ATOM_MEAN: &str = "com.apple.iTunes";
 
+/// ID3v2.4 relative volume adjustment frame, key of it in reports
+const RVA2: &str = "RVA2";
+/// identification of the RVA2 frame written
+const RVA2_TRACK: &str = "track";
+/// RVA2 channel type of master volume
+const RVA2_MASTER_VOLUME: u8 = 1;
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+/// ID3v2 version to write
+pub enum Id3v2version {
+    V3,
+    #[default]
+    V4,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid ID3v2 version '{0}'; only 3 and 4 are supported")]
+pub struct ParseId3v2versionError(String);
+
+impl std::str::FromStr for Id3v2version {
+    type Err = ParseId3v2versionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "3" => Ok(Self::V3),
+            "4" => Ok(Self::V4),
+            _ => Err(ParseId3v2versionError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+/// Tag type to write to formats that can carry several (e.g. APEv2 instead of ID3v2 in MP3)
+pub enum TagFormat {
+    Id3v2,
+    Ape,
+    Vorbis,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid tag type '{0}'; expected id3v2, apev2 or vorbis")]
+pub struct ParseTagFormatError(String);
+
+impl std::str::FromStr for TagFormat {
+    type Err = ParseTagFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "id3v2" => Ok(Self::Id3v2),
+            "apev2" => Ok(Self::Ape),
+            "vorbis" => Ok(Self::Vorbis),
+            _ => Err(ParseTagFormatError(s.to_owned())),
+        }
+    }
+}
+
+impl From<TagFormat> for TagType {
+    fn from(format: TagFormat) -> Self {
+        match format {
+            TagFormat::Id3v2 => TagType::ID3v2,
+            TagFormat::Ape => TagType::APE,
+            TagFormat::Vorbis => TagType::VorbisComments,
+        }
+    }
+}
+
+/// How RG tags are written
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions<'a> {
+    /// also write range and reference loudness tags
+    pub extended: bool,
+    /// unit of gains and ranges, "dB" or "LU"
+    pub unit: &'a str,
+    /// decimal places of gains, ranges and loudness (peaks always get 6)
+    pub precision: usize,
+    /// lowercase keys, where the tag type allows it
+    pub lowercase: bool,
+    /// only add tags that are not stored yet
+    pub keep_existing: bool,
+    /// strip other tag types (MP2/MP3, WavPack/APE)
+    pub strip: bool,
+    pub id3v2version: Id3v2version,
+    /// also write track gain and peak as RVA2 frame (ID3v2 only)
+    pub rva2: bool,
+    /// tag type instead of the format's default
+    pub tag_format: Option<TagFormat>,
+    /// LUFS written as REPLAYGAIN_REFERENCE_LOUDNESS instead of the scan target
+    pub reference_loudness: Option<f64>,
+}
+
+impl Default for WriteOptions<'_> {
+    fn default() -> Self {
+        Self {
+            extended: false,
+            unit: "dB",
+            precision: 2,
+            lowercase: false,
+            keep_existing: false,
+            strip: false,
+            id3v2version: Id3v2version::default(),
+            rva2: false,
+            tag_format: None,
+            reference_loudness: None,
+        }
+    }
+}
+
+/// Errors of reading and writing tags
+#[derive(Debug, thiserror::Error)]
+pub enum TagError {
+    #[error("Unsupported file type for tagging: {0}")]
+    Unsupported(PathBuf),
+    #[error("File is not writable: {0}")]
+    ReadOnly(PathBuf),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("FLAC tag error: {0}")]
+    Flac(#[from] metaflac::Error),
+    #[error("ID3 tag error: {0}")]
+    Id3(#[from] id3::Error),
+    #[error("Tag error: {0}")]
+    Lofty(#[from] lofty::LoftyError),
+}
+
+/// Write RG tags of `track_rg` and, if given, `album_rg` to `path`
+///
+/// Pass the same `album_rg` for every track of an album: album gain, peak (the highest track
+/// peak) and range are then identical in all its files. With `keep_existing` stored album
+/// tags stay, even if they are from another album scan.
 pub fn write_tags<P: AsRef<Path>>(
     path: P,
     track_rg: ReplayGain,
     album_rg: Option<ReplayGain>,
-    extended: bool,
-    unit: &str,
-    lowercase: bool,
-    strip: bool,
-    id3v2version: Id3v2version,
-) {
-    let mut tagger = get_tagger(path);
-    if strip {
+    opts: &WriteOptions,
+) -> Result<(), TagError> {
+    check_writable(&path)?;
+    let mut tagger = get_tagger(&path, opts.tag_format)?;
+    let tags = tagger.planned_tags(&track_rg, album_rg.as_ref(), opts);
+    // drop stale values (e.g. album tags from an earlier album scan)
+    if !opts.keep_existing {
         tagger.delete_tags();
     }
+    for (key, value) in tags {
+        tagger.set_tag(&key, value);
+    }
+    if opts.rva2 && !(opts.keep_existing && tagger.rva2().is_some()) {
+        tagger.set_rva2(track_rg.gain, track_rg.peak);
+    }
+    tagger.save(&path, opts.id3v2version)?;
+    if opts.strip {
+        strip_tags(path, tagger.tag_type());
+    }
+    Ok(())
+}
+
+/// Run `write` on a copy of `path` in the same directory, then rename the copy over `path`
+///
+/// If the process dies while writing, the original file stays untouched. Permissions are
+/// copied, ownership is kept where the user may set it. A symlink stays, its target is
+/// replaced.
+pub fn atomically<P, F>(path: P, write: F) -> Result<(), TagError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&Path) -> Result<(), TagError>,
+{
+    let path = &fs::canonicalize(path)?;
+    check_writable(path)?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+    // keeps the extension, which picks the tagger
+    let mut tmp_name = std::ffi::OsString::from(format!(".loudgainer-{}-", std::process::id()));
+    tmp_name.push(name);
+    let tmp = TempFile(path.with_file_name(tmp_name));
+    fs::copy(path, &tmp.0)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let meta = fs::metadata(path)?;
+        // only root may give files away
+        let _ = std::os::unix::fs::chown(&tmp.0, Some(meta.uid()), Some(meta.gid()));
+    }
+    write(&tmp.0)?;
+    // the copy must be on disk before it replaces the original
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&tmp.0)?
+        .sync_all()?;
+    Ok(fs::rename(&tmp.0, path)?)
+}
+
+/// File that is removed on drop (if it still exists)
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Whether tagging `path` is experimental (WAV/AIFF)
+///
+/// Decided by content, which also picks the tagger for extensionless or misnamed files; the
+/// extension only counts when the content is not recognized.
+pub fn is_experimental<P: AsRef<Path>>(path: P) -> bool {
+    let detected = Probe::open(&path)
+        .ok()
+        .and_then(|probe| probe.guess_file_type().ok())
+        .and_then(|probe| probe.file_type());
+    match detected {
+        Some(file_type) => matches!(file_type, FileType::WAV | FileType::AIFF),
+        None => {
+            matches!(
+                extension(path.as_ref()).as_deref(),
+                Some("wav" | "aiff" | "aif" | "snd")
+            )
+        }
+    }
+}
+
+/// Whether tags of `path` can be written at all
+///
+/// lofty can not read or write ASF/WMA and Matroska/WebM tags, so those files are only
+/// scanned.
+pub fn is_taggable<P: AsRef<Path>>(path: P) -> bool {
+    !matches!(
+        extension(path.as_ref()).as_deref(),
+        Some("asf" | "wma" | "mka" | "mkv" | "webm")
+    )
+}
+
+/// Whether `path` is an Ogg Opus file
+pub fn is_opus<P: AsRef<Path>>(path: P) -> bool {
+    extension(path.as_ref()).as_deref() == Some("opus")
+}
+
+/// Tags (key, value) that `write_tags` would write to `path`, without modifying it
+pub fn preview_tags<P: AsRef<Path>>(
+    path: P,
+    track_rg: ReplayGain,
+    album_rg: Option<ReplayGain>,
+    opts: &WriteOptions,
+) -> Result<Vec<(String, String)>, TagError> {
+    let tagger = get_tagger(&path, opts.tag_format)?;
+    let mut tags = tagger.planned_tags(&track_rg, album_rg.as_ref(), opts);
+    if opts.rva2 && tagger.has_rva2() && !(opts.keep_existing && tagger.rva2().is_some()) {
+        tags.push((RVA2.to_owned(), rva2_value(track_rg.gain, track_rg.peak)));
+    }
+    Ok(tags)
+}
+
+/// Track gain (dB) and peak (linear) of the RVA2 frame of `path`, if it has one
+///
+/// The frame identified as "track" is preferred, otherwise the first one is taken. Only the
+/// master volume channel is read.
+pub fn read_rva2<P: AsRef<Path>>(path: P) -> Result<Option<(f64, f64)>, TagError> {
+    Ok(get_tagger(&path, None)?.rva2())
+}
+
+/// ReplayGain tags currently stored in `path` (canonical key, value)
+pub fn read_tags<P: AsRef<Path>>(
+    path: P,
+    tag_format: Option<TagFormat>,
+) -> Result<Vec<(&'static str, String)>, TagError> {
+    Ok(get_tagger(&path, tag_format)?.existing_tags())
+}
+
+/// Whether `path` already carries exactly the tags `write_tags` would write
+///
+/// Numbers are compared with a small tolerance, so values written with a different precision
+/// (or by another tool) still count as tagged.
+pub fn is_tagged<P: AsRef<Path>>(
+    path: P,
+    track_rg: ReplayGain,
+    album_rg: Option<ReplayGain>,
+    opts: &WriteOptions,
+) -> Result<bool, TagError> {
+    const EPSILON: f64 = 0.01;
+    Ok(tag_mismatches(path, track_rg, album_rg, opts, EPSILON)?.is_empty())
+}
+
+/// RG tag of `path` that differs from what `write_tags` would write
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagMismatch {
+    /// canonical (uppercase) key
+    pub key: String,
+    /// value `write_tags` would write; None if the tag would be removed
+    pub expected: Option<String>,
+    /// stored value; None if the tag is missing
+    pub found: Option<String>,
+}
+
+/// Tags of `path` that are missing, stored with another value or would be removed
+///
+/// Numbers (gains, peaks, ranges) count as equal if they differ by at most `tolerance`,
+/// units are ignored then.
+pub fn tag_mismatches<P: AsRef<Path>>(
+    path: P,
+    track_rg: ReplayGain,
+    album_rg: Option<ReplayGain>,
+    opts: &WriteOptions,
+    tolerance: f64,
+) -> Result<Vec<TagMismatch>, TagError> {
+    let tagger = get_tagger(&path, opts.tag_format)?;
+    let opts = WriteOptions {
+        lowercase: false,
+        keep_existing: false,
+        ..*opts
+    };
+    let planned = tagger.planned_tags(&track_rg, album_rg.as_ref(), &opts);
+    let existing = tagger.existing_tags();
+    let mut mismatches: Vec<TagMismatch> = planned
+        .iter()
+        .filter_map(|(key, value)| {
+            let found = existing.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+            let matches = found.is_some_and(|v| match (tag_number(v), tag_number(value)) {
+                (Some(a), Some(b)) => (a - b).abs() <= tolerance,
+                _ => v == value,
+            });
+            (!matches).then(|| TagMismatch {
+                key: key.clone(),
+                expected: Some(value.clone()),
+                found: found.cloned(),
+            })
+        })
+        .collect();
+    mismatches.extend(
+        existing
+            .iter()
+            .filter(|(k, _)| !planned.iter().any(|(key, _)| key == k))
+            .map(|(k, v)| TagMismatch {
+                key: k.to_string(),
+                expected: None,
+                found: Some(v.clone()),
+            }),
+    );
+    if opts.rva2 && tagger.has_rva2() {
+        let found = tagger.rva2();
+        let matches = matches!(found, Some((gain, peak))
+            if (gain - track_rg.gain).abs() <= tolerance && (peak - track_rg.peak).abs() <= tolerance);
+        if !matches {
+            mismatches.push(TagMismatch {
+                key: RVA2.to_owned(),
+                expected: Some(rva2_value(track_rg.gain, track_rg.peak)),
+                found: found.map(|(gain, peak)| rva2_value(gain, peak)),
+            });
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Number at the start of a tag value, with or without a unit (`-6.50 dB`, `-6.50`)
+fn tag_number(value: &str) -> Option<f64> {
+    value
+        .split_whitespace()
+        .next()
+        .and_then(|n| n.parse::<f64>().ok())
+}
+
+/// RVA2 gain and peak as shown in reports
+fn rva2_value(gain: f64, peak: f64) -> String {
+    format!("{gain:.2} dB, peak {peak:.6}")
+}
+
+/// RVA2 frame content: identification, then master volume adjustment (dB * 512) and a
+/// 16-bit peak (full scale is 2^15)
+fn encode_rva2(gain: f64, peak: f64) -> Vec<u8> {
+    let mut data = RVA2_TRACK.as_bytes().to_vec();
+    data.push(0);
+    data.push(RVA2_MASTER_VOLUME);
+    let adjustment = (gain * 512.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    data.extend_from_slice(&adjustment.to_be_bytes());
+    data.push(16);
+    let peak = (peak * 32768.0).round().clamp(0.0, u16::MAX as f64) as u16;
+    data.extend_from_slice(&peak.to_be_bytes());
+    data
+}
+
+/// Identification, gain (dB) and peak (linear) of master volume in RVA2 frame content
+fn decode_rva2(data: &[u8]) -> Option<(String, f64, f64)> {
+    let end = data.iter().position(|&b| b == 0)?;
+    let identification = String::from_utf8_lossy(&data[..end]).into_owned();
+    let mut rest = &data[end + 1..];
+    while rest.len() >= 4 {
+        let channel = rest[0];
+        let gain = i16::from_be_bytes([rest[1], rest[2]]) as f64 / 512.0;
+        let bits = rest[3] as u32;
+        let len = bits.div_ceil(8) as usize;
+        let bytes = rest.get(4..4 + len)?;
+        if channel == RVA2_MASTER_VOLUME {
+            // peak is an unsigned integer of `bits` bits, full scale 2^(bits - 1)
+            let value = bytes.iter().fold(0_u64, |value, &b| value << 8 | b as u64);
+            let peak = if bits == 0 || bits > 64 {
+                0.0
+            } else {
+                value as f64 / 2_f64.powi(bits as i32 - 1)
+            };
+            return Some((identification, gain, peak));
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+/// Whether `frame` is an RVA2 frame identified as track (as written by [Tagger::set_rva2])
+fn is_track_rva2(frame: &Frame) -> bool {
+    match frame.content() {
+        Content::Unknown(unknown) => {
+            unknown
+                .data
+                .split(|&b| b == 0)
+                .next()
+                .is_some_and(|identification| {
+                    identification.eq_ignore_ascii_case(RVA2_TRACK.as_bytes())
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Strip tag types other than ID3v2 from MP2/MP3 (ID3v1, APEv2)
+/// and other than APEv2 from WavPack/APE (ID3v1); `keep` (the written one) always stays
+fn strip_tags<P: AsRef<Path>>(path: P, keep: TagType) {
+    let tag_types: &[TagType] = match extension(path.as_ref()).as_deref() {
+        Some("mp2" | "mp3") => &[TagType::ID3v1, TagType::APE],
+        Some("wv" | "ape") => &[TagType::ID3v1],
+        _ => &[],
+    };
+    for tt in tag_types.iter().filter(|&&tt| tt != keep) {
+        if let Err(e) = tt.remove_from_path(&path) {
+            warn!("Failed to strip {tt:?} tag: {e}");
+        }
+    }
 }
 
-pub fn delete_tags<P: AsRef<Path>>(path: P) {
-    let mut tagger = get_tagger(path);
+pub fn delete_tags<P: AsRef<Path>>(
+    path: P,
+    id3v2version: Id3v2version,
+    tag_format: Option<TagFormat>,
+) -> Result<(), TagError> {
+    check_writable(&path)?;
+    let mut tagger = get_tagger(&path, tag_format)?;
     tagger.delete_tags();
+    tagger.save(path, id3v2version)
 }
 
-fn get_tagger<P: AsRef<Path>>(path: P) -> Tagger {
-    match path
-        .as_ref()
-        .extension()
-        .unwrap()
-        .to_ascii_lowercase()
-        .to_string_lossy()
-        .as_ref()
-    {
-        "flac" => Tagger::Flacer(metaflac::Tag::read_from_path(path).unwrap()),
+/// Fail early for files we may not write, before anything is changed
+///
+/// Opening for writing (without truncating) asks the OS, so ownership and ACLs count too,
+/// not just the permission bits.
+fn check_writable<P: AsRef<Path>>(path: P) -> Result<(), TagError> {
+    match fs::OpenOptions::new().write(true).open(&path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Err(TagError::ReadOnly(path.as_ref().to_owned()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Tagger for `path`, writing `tag_format` if given and possible
+///
+/// An unsupported `tag_format` falls back to the default tag type of the format.
+fn get_tagger<P: AsRef<Path>>(path: P, tag_format: Option<TagFormat>) -> Result<Tagger, TagError> {
+    let tagger = default_tagger(&path)?;
+    let format = match tag_format {
+        Some(format) if TagType::from(format) != tagger.tag_type() => format,
+        _ => return Ok(tagger),
+    };
+    let forced = match (format, tagger) {
+        // lofty can not write TXXX frames, which is where ID3v2 keeps RG
+        (TagFormat::Id3v2, tagger) => Err(tagger),
+        (_, Tagger::Generic(t, _)) if t.supports_tag_type(format.into()) => {
+            Ok(Tagger::Generic(t, format.into()))
+        }
+        (_, Tagger::Id3(tag)) => {
+            match Probe::open(&path)
+                .ok()
+                .and_then(|probe| probe.guess_file_type().ok())
+                .and_then(|probe| probe.read(true).ok())
+            {
+                Some(t) if t.supports_tag_type(format.into()) => {
+                    Ok(Tagger::Generic(t, format.into()))
+                }
+                _ => Err(Tagger::Id3(tag)),
+            }
+        }
+        (_, tagger) => Err(tagger),
+    };
+    Ok(forced.unwrap_or_else(|tagger| {
+        warn!(
+            "Can not write {format:?} tags to {}, writing {:?}",
+            path.as_ref().display(),
+            tagger.tag_type()
+        );
+        tagger
+    }))
+}
+
+fn default_tagger<P: AsRef<Path>>(path: P) -> Result<Tagger, TagError> {
+    match extension(path.as_ref()).as_deref() {
+        Some("flac") => Ok(Tagger::Flacer(metaflac::Tag::read_from_path(path)?)),
+        Some("mp2" | "mp3" | "wav" | "aiff" | "aif") => read_id3(path),
         _ => {
             warn!("Using generic tegger");
-            let mut probe = Probe::open(path).unwrap();
-            if probe.file_type().is_none() {
-                probe = probe.guess_file_type().expect("Error: Bad file provided!");
+            // the extension only decides when content is not recognized, as it can be
+            // missing or wrong (e.g. Opus in .ogg, brand variants like .m4b)
+            let probe = Probe::open(&path)?.guess_file_type()?;
+            match probe.file_type() {
+                Some(FileType::MP3 | FileType::WAV | FileType::AIFF) => return read_id3(path),
+                Some(_) => {}
+                None => return Err(TagError::Unsupported(path.as_ref().to_owned())),
+            }
+            let mut tagged_file = probe.read(true)?;
+            if tagged_file.file_type() == FileType::MP4 {
+                add_freeform_rg(&mut tagged_file, &path);
             }
-            let tagged_file = probe.read(true).unwrap();
-            Tagger::Generic(tagged_file)
+            let tt = tagged_file.primary_tag_type();
+            Ok(Tagger::Generic(tagged_file, tt))
+        }
+    }
+}
+
+/// Add RG freeform atoms that lofty skipped (non-UTF-8 data type) to the ilst tag
+///
+/// Without them an RG value stored by another tagger would look missing. Writing the tag
+/// stores them as UTF-8.
+fn add_freeform_rg<P: AsRef<Path>>(tagged_file: &mut TaggedFile, path: P) {
+    let atoms = match mp4::read_freeform(&path, RG_ATOM_MEAN) {
+        Ok(atoms) => atoms,
+        Err(e) => {
+            warn!(
+                "Can not read freeform atoms of {}: {e}",
+                path.as_ref().display()
+            );
+            return;
         }
+    };
+    for (name, value) in atoms {
+        if !TAGS.iter().any(|tag| tag.eq_ignore_ascii_case(&name)) {
+            continue;
+        }
+        if tagged_file.tag(&TagType::MP4ilst).is_none() {
+            tagged_file.insert_tag(Tag::new(TagType::MP4ilst));
+        }
+        let ilst = tagged_file.tag_mut(&TagType::MP4ilst).unwrap();
+        let key = ItemKey::Unknown(RG_ATOM.to_owned() + &name);
+        if ilst.get_item_ref(&key).is_none() {
+            ilst.insert_item_unchecked(TagItem::new(key, ItemValue::Text(value)));
+        }
+    }
+}
+
+/// ID3v2 tag of MP2/MP3/WAV/AIFF, empty if there is none yet
+///
+/// WAV/AIFF get RG in their ID3 chunk (the id3 crate finds it by itself): RIFF INFO and AIFF
+/// text chunks only have fixed four-character keys. lofty's generic ID3v2 tag is lossy
+/// (e.g. TXXX descriptions), so the id3 crate is used, keeping unrelated frames intact.
+fn read_id3<P: AsRef<Path>>(path: P) -> Result<Tagger, TagError> {
+    match id3::Tag::read_from_path(path) {
+        Ok(tag) => Ok(Tagger::Id3(tag)),
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => Ok(Tagger::Id3(id3::Tag::new())),
+        Err(e) => Err(e.into()),
     }
 }
 
 enum Tagger {
     Flacer(metaflac::Tag),
-    Generic(TaggedFile),
+    Id3(id3::Tag),
+    /// file and the tag type RG is written to
+    Generic(TaggedFile, TagType),
 }
 
 impl Tagger {
@@ -77,18 +614,48 @@ impl Tagger {
                     t.remove_vorbis(tag);
                 }
             }
-            Tagger::Generic(t) => {
+            Tagger::Id3(t) => {
+                // match TXXX descriptions case-insensitively, so lowercase variants go too
+                let descriptions: Vec<String> = t
+                    .extended_texts()
+                    .map(|ext| ext.description.clone())
+                    .filter(|desc| TAGS.iter().any(|tag| tag.eq_ignore_ascii_case(desc)))
+                    .collect();
+                for desc in descriptions {
+                    t.remove_extended_text(Some(&desc), None);
+                }
+                // the RVA2 frame of --rva2; other RVA2 frames are not ours and stay
+                let others: Vec<Frame> = t
+                    .remove(RVA2)
+                    .into_iter()
+                    .filter(|frame| !is_track_rva2(frame))
+                    .collect();
+                t.extend(others);
+            }
+            Tagger::Generic(t, _) => {
                 let vtt: Vec<TagType> = t.tags().iter().map(|x| x.tag_type()).collect();
                 for tt in vtt {
                     let ttt = t.tag_mut(&tt).unwrap();
                     match tt {
-                        TagType::APE => todo!(),
-                        TagType::ID3v1 => todo!(),
-                        TagType::ID3v2 => todo!(),
+                        TagType::APE => {
+                            // APE item keys are case-insensitive
+                            ttt.retain_items(|item| {
+                                !matches!(item.key(), ItemKey::Unknown(key)
+                                    if TAGS.iter().any(|tag| tag.eq_ignore_ascii_case(key)))
+                            });
+                        }
+                        // ID3v1 has no place for RG, it can only be stripped as a whole
+                        TagType::ID3v1 => {}
+                        TagType::ID3v2 => {
+                            // lofty drops TXXX descriptions, so RG frames can not be told apart
+                            warn!("Can not remove ReplayGain TXXX frames from this ID3v2 tag");
+                        }
                         TagType::MP4ilst => {
+                            // only the RG name is ever lowercased, never the atom prefix
                             for tag in TAGS {
+                                ttt.remove_key(&lofty::ItemKey::Unknown(RG_ATOM.to_owned() + tag));
                                 ttt.remove_key(&lofty::ItemKey::Unknown(
-                                    RG_ATOM.to_ascii_uppercase() + tag,
+                                    RG_ATOM.to_owned() + &tag.to_ascii_lowercase(),
                                 ));
                             }
                         }
@@ -98,14 +665,300 @@ impl Tagger {
                                 ttt.remove_key(&lofty::ItemKey::Unknown(tag.to_ascii_lowercase()));
                             }
                         }
-                        _ => todo!(),
+                        _ => warn!("Can not remove ReplayGain tags from {tt:?} tag"),
                     }
                 }
             }
         }
     }
 
-    fn set_album_tags(&mut self, unit: &str) {}
+    /// Stored RG tags, looked up case-insensitively
+    fn existing_tags(&self) -> Vec<(&'static str, String)> {
+        TAGS.iter()
+            .filter_map(|&tag| self.get_tag(tag).map(|value| (tag, value)))
+            .collect()
+    }
+
+    /// Get one tag value, whatever its casing
+    fn get_tag(&self, key: &str) -> Option<String> {
+        match self {
+            // metaflac uppercases keys on lookup
+            Tagger::Flacer(t) => t
+                .get_vorbis(key)
+                .and_then(|mut values| values.next())
+                .map(str::to_owned),
+            Tagger::Id3(t) => t
+                .extended_texts()
+                .find(|ext| ext.description.eq_ignore_ascii_case(key))
+                .map(|ext| ext.value.clone()),
+            Tagger::Generic(t, _) => t.tags().iter().find_map(|tag| {
+                tag.items().iter().find_map(|item| match item.key() {
+                    ItemKey::Unknown(k)
+                        if k.strip_prefix(RG_ATOM)
+                            .unwrap_or(k)
+                            .eq_ignore_ascii_case(key) =>
+                    {
+                        item.value().text().map(str::to_owned)
+                    }
+                    _ => None,
+                })
+            }),
+        }
+    }
 
-    fn set_track_tags(&mut self, extended: bool, unit: &str) {}
+    /// Whether this tag type may carry lowercase RG tags
+    ///
+    /// Lowercase is non-standard, so it is only used where players are known to expect it.
+    /// Lowercase keys are written by [Tagger::planned_tags] and removed again by
+    /// [Tagger::delete_tags]. ASF/WMA would qualify too, but lofty can not tag it yet.
+    fn allows_lowercase(&self) -> bool {
+        match self {
+            Tagger::Flacer(_) => false,
+            Tagger::Id3(_) => true,
+            Tagger::Generic(_, tt) => matches!(
+                tt,
+                TagType::ID3v2 | TagType::MP4ilst | TagType::RIFFInfo | TagType::AIFFText
+            ),
+        }
+    }
+
+    /// Set (replace) one tag value
+    fn set_tag(&mut self, key: &str, value: String) {
+        match self {
+            Tagger::Flacer(t) => t.set_vorbis(key, vec![value]),
+            // ID3v2 stores RG as TXXX frames, described by the tag name
+            Tagger::Id3(t) => {
+                t.add_frame(ExtendedText {
+                    description: key.to_owned(),
+                    value,
+                });
+            }
+            Tagger::Generic(t, tt) => {
+                let tt = *tt;
+                if t.tag(&tt).is_none() {
+                    t.insert_tag(Tag::new(tt));
+                }
+                let key = match tt {
+                    TagType::MP4ilst => RG_ATOM.to_owned() + key,
+                    _ => key.to_owned(),
+                };
+                t.tag_mut(&tt).unwrap().insert_item_unchecked(TagItem::new(
+                    ItemKey::Unknown(key),
+                    ItemValue::Text(value),
+                ));
+            }
+        }
+    }
+
+    fn save<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        id3v2version: Id3v2version,
+    ) -> Result<(), TagError> {
+        match self {
+            Tagger::Flacer(t) => t.save()?,
+            Tagger::Id3(t) => t.write_to_path(path, id3v2version.into())?,
+            // tag by tag: lofty would rewrite an ID3v2 tag lossily (TXXX descriptions are
+            // dropped), and RG never goes there (see [get_tagger]), so it is left as it is
+            Tagger::Generic(t, _) => {
+                for tag in t
+                    .tags()
+                    .iter()
+                    .filter(|tag| tag.tag_type() != TagType::ID3v2)
+                {
+                    tag.save_to_path(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether RVA2 frames can be stored (ID3v2)
+    fn has_rva2(&self) -> bool {
+        matches!(self, Tagger::Id3(_))
+    }
+
+    /// Gain and peak of the stored RVA2 frame, see [read_rva2]
+    fn rva2(&self) -> Option<(f64, f64)> {
+        let t = match self {
+            Tagger::Id3(t) => t,
+            _ => return None,
+        };
+        let frames: Vec<(String, f64, f64)> = t
+            .frames()
+            .filter(|frame| frame.id() == RVA2)
+            .filter_map(|frame| match frame.content() {
+                Content::Unknown(unknown) => decode_rva2(&unknown.data),
+                _ => None,
+            })
+            .collect();
+        frames
+            .iter()
+            .find(|(identification, ..)| identification.eq_ignore_ascii_case(RVA2_TRACK))
+            .or_else(|| frames.first())
+            .map(|&(_, gain, peak)| (gain, peak))
+    }
+
+    /// Replace all RVA2 frames by one with track `gain` and `peak` (ID3v2 only)
+    fn set_rva2(&mut self, gain: f64, peak: f64) {
+        if let Tagger::Id3(t) = self {
+            t.remove(RVA2);
+            t.add_frame(Frame::with_content(
+                RVA2,
+                Content::Unknown(Unknown {
+                    data: encode_rva2(gain, peak),
+                    version: id3::Version::Id3v24,
+                }),
+            ));
+        }
+    }
+
+    /// Opus has its own R128_* gain tags
+    fn is_opus(&self) -> bool {
+        matches!(self, Tagger::Generic(t, _) if t.file_type() == FileType::Opus)
+    }
+
+    /// Tag type RG is written to
+    fn tag_type(&self) -> TagType {
+        match self {
+            Tagger::Flacer(_) => TagType::VorbisComments,
+            Tagger::Id3(_) => TagType::ID3v2,
+            Tagger::Generic(_, tt) => *tt,
+        }
+    }
+
+    /// RG tags to set for `track_rg` (and `album_rg`), named as they will be stored
+    ///
+    /// With `keep_existing`, tags that are already stored (in any casing) are left out.
+    fn planned_tags(
+        &self,
+        track_rg: &ReplayGain,
+        album_rg: Option<&ReplayGain>,
+        opts: &WriteOptions,
+    ) -> Vec<(String, String)> {
+        let WriteOptions {
+            unit, precision, ..
+        } = *opts;
+        let mut tags = vec![
+            (
+                "REPLAYGAIN_TRACK_GAIN",
+                format!("{:.precision$} {unit}", track_rg.gain),
+            ),
+            ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", track_rg.peak)),
+        ];
+        if self.is_opus() {
+            tags.push(("R128_TRACK_GAIN", track_rg.r128_gain().to_string()));
+        }
+        if opts.extended {
+            tags.push((
+                "REPLAYGAIN_TRACK_RANGE",
+                format!("{:.precision$} {unit}", track_rg.loudness_range),
+            ));
+            tags.push((
+                "REPLAYGAIN_REFERENCE_LOUDNESS",
+                format!(
+                    "{:.precision$} LUFS",
+                    opts.reference_loudness
+                        .unwrap_or(track_rg.loudness_reference)
+                ),
+            ));
+        }
+        if let Some(rg) = album_rg {
+            tags.push((
+                "REPLAYGAIN_ALBUM_GAIN",
+                format!("{:.precision$} {unit}", rg.gain),
+            ));
+            tags.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", rg.peak)));
+            if self.is_opus() {
+                tags.push(("R128_ALBUM_GAIN", rg.r128_gain().to_string()));
+            }
+            if opts.extended {
+                tags.push((
+                    "REPLAYGAIN_ALBUM_RANGE",
+                    format!("{:.precision$} {unit}", rg.loudness_range),
+                ));
+            }
+        }
+        let lowercase = opts.lowercase && self.allows_lowercase();
+        tags.into_iter()
+            .filter(|(key, _)| !opts.keep_existing || self.get_tag(key).is_none())
+            .map(|(key, value)| {
+                if lowercase {
+                    (key.to_ascii_lowercase(), value)
+                } else {
+                    (key.to_owned(), value)
+                }
+            })
+            .collect()
+    }
+}
+
+impl From<Id3v2version> for id3::Version {
+    fn from(v: Id3v2version) -> Self {
+        match v {
+            Id3v2version::V3 => id3::Version::Id3v23,
+            Id3v2version::V4 => id3::Version::Id3v24,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id3v2version_parses_3_and_4() {
+        assert_eq!("3".parse::<Id3v2version>().unwrap(), Id3v2version::V3);
+        assert_eq!("4".parse::<Id3v2version>().unwrap(), Id3v2version::V4);
+    }
+
+    #[test]
+    fn id3v2version_rejects_empty_and_unknown() {
+        for s in ["", "2", "5", " 4", "v4"] {
+            let err = s.parse::<Id3v2version>().unwrap_err();
+            assert!(err.to_string().contains(&format!("'{s}'")), "{err}");
+        }
+    }
+
+    #[test]
+    fn tag_number_ignores_unit() {
+        assert_eq!(tag_number("-6.50 dB"), Some(-6.5));
+        assert_eq!(tag_number("-6.50"), Some(-6.5));
+        assert_eq!(tag_number("0.988000"), Some(0.988));
+        assert_eq!(tag_number("-18.00 LUFS"), Some(-18.0));
+        assert_eq!(tag_number("dB"), None);
+        assert_eq!(tag_number(""), None);
+    }
+
+    #[test]
+    fn tag_format_parses_case_insensitively() {
+        assert_eq!("ID3v2".parse::<TagFormat>().unwrap(), TagFormat::Id3v2);
+        assert_eq!("APEv2".parse::<TagFormat>().unwrap(), TagFormat::Ape);
+        assert_eq!("vorbis".parse::<TagFormat>().unwrap(), TagFormat::Vorbis);
+        assert!("mp4".parse::<TagFormat>().is_err());
+    }
+
+    #[test]
+    fn rva2_round_trip() {
+        for (gain, peak) in [(0.0, 0.0), (-7.25, 0.988), (12.5, 0.25), (-0.01, 1.0)] {
+            let (identification, back_gain, back_peak) =
+                decode_rva2(&encode_rva2(gain, peak)).unwrap();
+            assert_eq!(identification, RVA2_TRACK);
+            // gain in 1/512 dB, peak in 1/32768 steps
+            assert!(
+                (back_gain - gain).abs() <= 0.5 / 512.0,
+                "{gain} -> {back_gain}"
+            );
+            assert!(
+                (back_peak - peak).abs() <= 0.5 / 32768.0,
+                "{peak} -> {back_peak}"
+            );
+        }
+    }
+
+    #[test]
+    fn rva2_gain_saturates() {
+        let (_, gain, _) = decode_rva2(&encode_rva2(100.0, 0.5)).unwrap();
+        assert_eq!(gain, i16::MAX as f64 / 512.0);
+    }
 }